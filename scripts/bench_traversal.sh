@@ -0,0 +1,75 @@
+#!/usr/bin/env bash
+# Compares initial scan times of the working tree against another revision on synthetic trees, at each of the given
+# `-fdl` values
+#   wide: 2000 dirs of 50 files directly under the root
+#   deep: 4 chains of 1000 nested dirs with 10 files each, next to 200 shallow dirs of 50 files
+# Usage: scripts/bench_traversal.sh <base_rev> [runs] [fdl...]
+#   e.g. scripts/bench_traversal.sh <rev> 5 256 2048 16384, with <rev> the last revision with round-based redistribution
+set -euo pipefail
+
+BASE_REV="${1:?usage: scripts/bench_traversal.sh <base_rev> [runs] [fdl...]}"
+RUNS="${2:-5}"
+shift $(( $# < 2 ? $# : 2 ))
+FDLS=("$@")
+if [ "${#FDLS[@]}" -eq 0 ]; then
+    FDLS=(2048)
+fi
+REPO="$(cd "$(dirname "$0")/.." && pwd)"
+WORK="$(mktemp -d)"
+trap 'git -C "$REPO" worktree remove --force "$WORK/base" >/dev/null 2>&1 || true; rm -rf "$WORK"' EXIT
+
+make_files() {
+    for f in $(seq 1 "$2"); do
+        echo "$f" > "$1/f$f"
+    done
+}
+
+echo "Generating trees in $WORK"
+mkdir -p "$WORK/wide"
+for d in $(seq 1 2000); do
+    mkdir "$WORK/wide/d$d"
+    make_files "$WORK/wide/d$d" 50
+done
+mkdir -p "$WORK/deep"
+for c in $(seq 1 4); do
+    p="$WORK/deep/c$c"
+    for l in $(seq 1 1000); do
+        p="$p/d"
+        mkdir -p "$p"
+        make_files "$p" 10
+    done
+done
+for d in $(seq 1 200); do
+    mkdir "$WORK/deep/s$d"
+    make_files "$WORK/deep/s$d" 50
+done
+
+echo "Building $BASE_REV and working tree"
+git -C "$REPO" worktree add --detach "$WORK/base" "$BASE_REV" >/dev/null
+cargo build --release --quiet --manifest-path "$WORK/base/Cargo.toml"
+cargo build --release --quiet --manifest-path "$REPO/Cargo.toml"
+
+# Prints the mean wall time in ms of `RUNS` initial scans
+bench() {
+    local bin="$1" tree="$2" fdl="$3" total=0
+    for _ in $(seq 1 "$RUNS"); do
+        rm -rf "$WORK/out" && mkdir "$WORK/out"
+        local start end
+        start=$(date +%s%N)
+        "$bin" scan -fdl "$fdl" "$tree" "$WORK/out" > /dev/null
+        end=$(date +%s%N)
+        total=$((total + (end - start) / 1000000))
+    done
+    echo $((total / RUNS))
+}
+
+echo "$(nproc) CPUs, mean of $RUNS runs"
+if [ "$(nproc)" -eq 1 ]; then
+    echo "warning: with 1 CPU there's nothing to steal, these numbers say nothing about work-stealing on multi-core machines" >&2
+fi
+printf "%-6s %6s %12s %12s\n" "tree" "fdl" "$BASE_REV" "current"
+for tree in wide deep; do
+    for fdl in "${FDLS[@]}"; do
+        printf "%-6s %6s %10sms %10sms\n" "$tree" "$fdl" "$(bench "$WORK/base/target/release/seye_rs" "$WORK/$tree" "$fdl")" "$(bench "$REPO/target/release/seye_rs" "$WORK/$tree" "$fdl")"
+    done
+done
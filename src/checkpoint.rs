@@ -0,0 +1,239 @@
+use std::{collections::HashSet, fs::{exists, File, OpenOptions}, io::{BufWriter, Read, Write}, path::PathBuf, sync::{atomic::{AtomicBool, Ordering}, mpsc::{Receiver, RecvTimeoutError}, Mutex}, time::Duration};
+use serde::{Deserialize, Serialize};
+use crate::{scan::ScanOptions, spill::{get_entry_mem_size, remove_runs, ExternalSorter, SortedRuns}, walk::{CDirEntry, WalkError}};
+
+// How often a running walk is saved to the checkpoint file
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+    // A walk stuck in a hung `stat` or `readdir` (e.g. NFS) never gets to stop, so a second signal kills the process
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_DFL);
+        libc::signal(libc::SIGTERM, libc::SIG_DFL);
+    }
+}
+
+// The first SIGINT or SIGTERM stops the walk at the next directory instead of killing the process, so the checkpoint is
+// complete. A second one kills it
+pub fn handle_interrupts() {
+    let handler = on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+pub fn is_interrupted() -> bool {
+    return INTERRUPTED.load(Ordering::SeqCst);
+}
+
+// Everything a walk has collected so far. `pending` holds the dirs of walk tasks that haven't finished, walking them
+// and adding the results to `entries` (and `spill`) always gives the full tree
+#[derive(Default)]
+pub struct WalkState {
+    pub entries: Vec<CDirEntry>,
+    pub errors: Vec<WalkError>,
+    pub pending: HashSet<PathBuf>,
+    // Number of `entries` and `errors` already in the checkpoint file
+    saved: (usize, usize),
+    // With `--memory-limit`, `entries` are moved to sorted runs once they use more than the limit
+    spill: Option<ExternalSorter<CDirEntry>>,
+    entries_bytes: usize,
+    pub spill_error: Option<std::io::Error>,
+}
+impl WalkState {
+    pub fn new(roots: &Vec<PathBuf>) -> Self {
+        return WalkState {
+            pending: roots.iter().cloned().collect(),
+            ..Default::default()
+        };
+    }
+
+    pub fn set_spill(&mut self, spill: ExternalSorter<CDirEntry>) {
+        self.spill = Some(spill);
+    }
+
+    // Adds the results of a walk task, which replace its dir in `pending` with the dirs it didn't get to
+    pub fn add_results(&mut self, mut entries: Vec<CDirEntry>, mut errors: Vec<WalkError>, walked: &PathBuf, leftover: &Vec<PathBuf>) {
+        if self.spill.is_some() {
+            self.entries_bytes += entries.iter().map(get_entry_mem_size).sum::<usize>();
+        }
+        self.entries.append(&mut entries);
+        self.errors.append(&mut errors);
+        self.pending.remove(walked);
+        self.pending.extend(leftover.iter().cloned());
+
+        let Some(spill) = self.spill.as_mut() else { return };
+        if self.entries_bytes <= spill.limit() {
+            return;
+        }
+        // Spilled entries are only in the run now, the next checkpoint batch lists the run instead
+        let res = spill.write_run(std::mem::take(&mut self.entries));
+        self.entries_bytes = 0;
+        self.saved.0 = 0;
+        if res.is_err() && self.spill_error.is_none() {
+            self.spill_error = res.err();
+        }
+    }
+
+    pub fn num_spilled(&self) -> usize {
+        return self.spill.as_ref().map(|sp| sp.num_in_runs()).unwrap_or(0);
+    }
+
+    // Sorts everything the walk collected, entries left in memory become the last run
+    pub fn finish_spill(&mut self) -> std::io::Result<Option<SortedRuns<CDirEntry>>> {
+        let Some(mut spill) = self.spill.take() else { return Ok(None) };
+        spill.write_run(std::mem::take(&mut self.entries))?;
+        return Ok(Some(spill.finish(false)?));
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+enum Record {
+    Header { roots: Vec<PathBuf>, options: ScanOptions },
+    // Entries and errors collected since the previous batch, with ALL the pending dirs and spilled runs at the time.
+    // A batch with more runs than the one before replaces the entries of the batches since the last new run
+    Batch { entries: Vec<CDirEntry>, errors: Vec<WalkError>, pending: Vec<PathBuf>, runs: Vec<PathBuf>, num_in_runs: usize },
+}
+
+// Sidecar of an unfinished scan in the output directory, batches are appended so each save only writes what's new
+pub struct Checkpoint {
+    path: PathBuf,
+    dir: PathBuf,
+    run_prefix: String,
+}
+impl Checkpoint {
+    pub fn new(output_path: &PathBuf, root_path_hash: &str) -> Self {
+        return Checkpoint {
+            path: output_path.join(format!("{}_checkpoint", root_path_hash)),
+            dir: output_path.clone(),
+            run_prefix: format!("{}_walk_run_", root_path_hash),
+        };
+    }
+
+    // Spills the walk to runs next to the checkpoint, so they're kept with it
+    pub fn new_spill(&self, limit: usize) -> ExternalSorter<CDirEntry> {
+        return ExternalSorter::new(&self.dir, &self.run_prefix, limit, get_entry_mem_size, |a, b| a.p.cmp(&b.p));
+    }
+
+    pub fn exists(&self) -> std::io::Result<bool> {
+        return exists(&self.path);
+    }
+
+    // Starts an empty checkpoint, replacing any previous one
+    pub fn create(&self, roots: &Vec<PathBuf>, options: &ScanOptions) -> std::io::Result<()> {
+        remove_runs(&self.dir, &self.run_prefix, &vec![])?;
+        let f = File::create(&self.path)?;
+        return write_record(f, &Record::Header { roots: roots.clone(), options: options.clone() });
+    }
+
+    // Appends what `state` collected since the last save
+    pub fn save(&self, state: &Mutex<WalkState>) -> std::io::Result<()> {
+        let (batch, num_entries, num_errors, num_runs) = {
+            let s = state.lock().unwrap();
+            let runs = s.spill.as_ref().map(|sp| sp.runs().clone()).unwrap_or_default();
+            let num_runs = runs.len();
+            let batch = Record::Batch {
+                entries: s.entries[s.saved.0..].to_vec(),
+                errors: s.errors[s.saved.1..].to_vec(),
+                pending: s.pending.iter().cloned().collect(),
+                runs: runs,
+                num_in_runs: s.spill.as_ref().map(|sp| sp.num_in_runs()).unwrap_or(0),
+            };
+            (batch, s.entries.len(), s.errors.len(), num_runs)
+        };
+
+        let f = OpenOptions::new().append(true).open(&self.path)?;
+        write_record(f, &batch)?;
+        // If a run was spilled while writing, its entries are counted from the new run instead
+        let mut s = state.lock().unwrap();
+        let curr_num_runs = s.spill.as_ref().map(|sp| sp.runs().len()).unwrap_or(0);
+        if curr_num_runs == num_runs {
+            s.saved = (num_entries, num_errors);
+        } else {
+            s.saved.1 = num_errors;
+        }
+        return Ok(());
+    }
+
+    // Saves every `CHECKPOINT_INTERVAL`, then once more when `stop` is signalled or dropped. A walk that finishes within the
+    // first interval isn't saved unless it was interrupted, walking it again is cheaper than writing every entry out
+    pub fn save_until_stopped(&self, state: &Mutex<WalkState>, stop: Receiver<()>) -> std::io::Result<()> {
+        let mut num_saves = 0;
+        while stop.recv_timeout(CHECKPOINT_INTERVAL) == Err(RecvTimeoutError::Timeout) {
+            let res = self.save(state);
+            if res.is_err() {
+                eprintln!("failed to save checkpoint {:?}: {}", self.path, res.err().unwrap());
+            }
+            num_saves += 1;
+        }
+        if num_saves == 0 && !is_interrupted() {
+            return Ok(());
+        }
+        return self.save(state);
+    }
+
+    // Reads back the walk of an unfinished scan. A partly written last batch (e.g. from a crash) is dropped from the file,
+    // along with runs it doesn't list
+    pub fn load(&self, roots: &Vec<PathBuf>, options: &ScanOptions, maybe_memory_limit: Option<usize>) -> std::io::Result<WalkState> {
+        let mut buf = vec![];
+        File::open(&self.path)?.read_to_end(&mut buf)?;
+        let mut rest: &[u8] = &buf;
+
+        let header = bincode::deserialize_from::<_, Record>(&mut rest);
+        match header {
+            Ok(Record::Header { roots: ck_roots, options: ck_options }) => {
+                if ck_roots != *roots || ck_options != *options {
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("checkpoint {:?} is for {:?} with options {:?}, rerun with those or without --resume", self.path, ck_roots, ck_options)));
+                }
+            }
+            _ => {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("checkpoint {:?} has no valid header", self.path)));
+            }
+        }
+
+        let mut state = WalkState::new(roots);
+        let mut valid_len = buf.len() - rest.len();
+        let mut spilled: (Vec<PathBuf>, usize) = (vec![], 0);
+        while let Ok(Record::Batch { mut entries, mut errors, pending, runs, num_in_runs }) = bincode::deserialize_from::<_, Record>(&mut rest) {
+            if runs.len() != spilled.0.len() {
+                state.entries.clear();
+            }
+            state.entries.append(&mut entries);
+            state.errors.append(&mut errors);
+            state.pending = pending.into_iter().collect();
+            spilled = (runs, num_in_runs);
+            valid_len = buf.len() - rest.len();
+        }
+        OpenOptions::new().write(true).open(&self.path)?.set_len(valid_len as u64)?;
+        remove_runs(&self.dir, &self.run_prefix, &spilled.0)?;
+
+        if spilled.0.len() > 0 && maybe_memory_limit.is_none() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("checkpoint {:?} spilled to disk, resume it with --memory-limit", self.path)));
+        }
+        if let Some(limit) = maybe_memory_limit {
+            state.entries_bytes = state.entries.iter().map(get_entry_mem_size).sum();
+            state.spill = Some(self.new_spill(limit).with_runs(spilled.0, spilled.1));
+        }
+        state.saved = (state.entries.len(), state.errors.len());
+        return Ok(state);
+    }
+
+    pub fn remove(&self) -> std::io::Result<()> {
+        remove_runs(&self.dir, &self.run_prefix, &vec![])?;
+        return std::fs::remove_file(&self.path);
+    }
+}
+
+fn write_record(f: File, r: &Record) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(f);
+    let res = bincode::serialize_into(&mut writer, r);
+    if res.is_err() {
+        return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("failed to serialise checkpoint: {}", res.err().unwrap())));
+    }
+    writer.flush()?;
+    return writer.get_ref().sync_data();
+}
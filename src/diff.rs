@@ -35,17 +35,43 @@ pub fn get_diff_type_shorthand(id: usize) -> String {
 pub struct FileEntryDiff {
     pub bn: std::ffi::OsString,
     pub sz: i128,
+    pub alloc_sz: i128,
     pub t_diff: TDiff,
+
+    // Not diffs, these are the latest values
+    pub dev: u64,
+    pub ino: u64,
+    pub nlink: u64,
+    pub target: Option<std::path::PathBuf>,
+    pub dangling: bool,
+    pub hash: Option<[u8; 16]>,
+    // Set when the content hash changed, even if the size didn't
+    pub hash_changed: bool,
+    pub kind: walk::FileKind,
+    // The latest attributes, and the ones before if they changed
+    pub attrs: walk::Attrs,
+    pub old_attrs: Option<walk::Attrs>,
 }
 impl Default for FileEntryDiff {
     fn default() -> Self {
         FileEntryDiff {
             bn: std::ffi::OsString::new(),
             sz: 0,
+            alloc_sz: 0,
             t_diff: TDiff{
                 s_diff: 0,
                 ns_diff: 0,
             },
+            dev: 0,
+            ino: 0,
+            nlink: 0,
+            target: None,
+            dangling: false,
+            hash: None,
+            hash_changed: false,
+            kind: walk::FileKind::File,
+            attrs: walk::Attrs::default(),
+            old_attrs: None,
         }
     }
 }
@@ -80,9 +106,22 @@ pub struct CDirEntryDiff {
     pub dirs_below: usize,
     pub size_here: i64,
     pub size_below: i64,
+    pub unique_size_here: i64,
+    pub unique_size_below: i64,
+    pub alloc_here: i64,
+    pub alloc_below: i64,
+    pub specials_here: usize,
+    pub specials_below: usize,
 
     pub files: [Vec<FileEntryDiff>; NUM_DT],
-    pub symlinks: [Vec<FileEntryDiff>; NUM_DT]
+    pub symlinks: [Vec<FileEntryDiff>; NUM_DT],
+    pub specials: [Vec<FileEntryDiff>; NUM_DT],
+    // Same as for `FileEntryDiff`
+    pub attrs: walk::Attrs,
+    pub old_attrs: Option<walk::Attrs>,
+    pub not_traversed: bool,
+    // Not a diff, the latest ctime
+    pub ct: Option<std::time::SystemTime>,
 }
 
 pub fn add_diffs_to_items<I: Clone + std::fmt::Debug + PartialEq, D: Clone + std::fmt::Debug>(
@@ -181,6 +220,15 @@ pub fn merge_dir_diff_to_entry(ent: &mut walk::CDirEntry, d: CDirEntryDiff) {
     ent.dirs_below += d.dirs_below;
     ent.size_here += d.size_here;
     ent.size_below += d.size_below;
+    ent.unique_size_here += d.unique_size_here;
+    ent.unique_size_below += d.unique_size_below;
+    ent.alloc_here += d.alloc_here;
+    ent.alloc_below += d.alloc_below;
+    ent.specials_here = ent.specials_here.wrapping_add(d.specials_here);
+    ent.specials_below = ent.specials_below.wrapping_add(d.specials_below);
+    ent.attrs = d.attrs;
+    ent.not_traversed = d.not_traversed;
+    ent.ct = d.ct;
 
     let mut files_vec = ent.files.to_vec();
     _ = add_diffs_to_items::<walk::FileEntry, FileEntryDiff>(&mut files_vec, &mut d.files.clone(),
@@ -200,26 +248,55 @@ pub fn merge_dir_diff_to_entry(ent: &mut walk::CDirEntry, d: CDirEntryDiff) {
     merge_file_diff_to_entry);
     ent.symlinks = symlinks_vec;
 
+    let mut specials_vec = ent.specials.to_vec();
+    _ = add_diffs_to_items::<walk::FileEntry, FileEntryDiff>(&mut specials_vec, &mut d.specials.clone(),
+    |a, b|{return a.bn.cmp(&b.bn)}, 
+    |it, d| {return it.bn == d.bn},
+    ignore_file_entry, 
+    get_entry_from_file_diff, 
+    merge_file_diff_to_entry);
+    ent.specials = specials_vec;
+
 }
 
 pub fn merge_file_diff_to_entry(ent: &mut walk::FileEntry, d: FileEntryDiff) {
     ent.md = t_diff_to_system_time(d.t_diff, ent.md);
-    ent.sz = d.sz as u64;
+    // The sizes of a MOD are changes, ADDs aren't merged into an existing entry
+    ent.sz = (ent.sz as i128 + d.sz) as u64;
+    ent.alloc_sz = (ent.alloc_sz as i128 + d.alloc_sz) as u64;
+    ent.dev = d.dev;
+    ent.ino = d.ino;
+    ent.nlink = d.nlink;
+    ent.target = d.target;
+    ent.dangling = d.dangling;
+    ent.hash = d.hash;
+    ent.kind = d.kind;
+    ent.attrs = d.attrs;
 }
 
 pub fn get_entry_from_dir_diff(d: CDirEntryDiff) -> walk::CDirEntry {
     let mut ret = walk::CDirEntry {
         p: d.p,
         md: t_diff_to_system_time(d.t_diff, None),
+        ct: d.ct,
         files_here: d.files_here,
         files_below: d.files_below,
         dirs_here: d.dirs_here,
         dirs_below: d.dirs_below,
         size_here: d.size_here,
         size_below: d.size_below,
+        unique_size_here: d.unique_size_here,
+        unique_size_below: d.unique_size_below,
+        alloc_here: d.alloc_here,
+        alloc_below: d.alloc_below,
+        specials_here: d.specials_here,
+        specials_below: d.specials_below,
         md5: [0; 16],
         files: get_f_entries_from_f_diffs(d.files),
         symlinks: get_f_entries_from_f_diffs(d.symlinks),
+        specials: get_f_entries_from_f_diffs(d.specials),
+        attrs: d.attrs,
+        not_traversed: d.not_traversed,
     };
     ret.md5 = utility::get_md5_of_cdirentry(ret.clone());
     
@@ -230,7 +307,16 @@ pub fn get_entry_from_file_diff(d: FileEntryDiff) -> walk::FileEntry {
     return walk::FileEntry {
         bn: d.bn,
         sz: d.sz as u64,
+        alloc_sz: d.alloc_sz as u64,
         md: t_diff_to_system_time(d.t_diff, None),
+        dev: d.dev,
+        ino: d.ino,
+        nlink: d.nlink,
+        target: d.target,
+        dangling: d.dangling,
+        hash: d.hash,
+        kind: d.kind,
+        attrs: d.attrs,
     }
 }
 
@@ -262,12 +348,32 @@ pub fn t_diff_to_system_time(td: TDiff, old_md: Option<std::time::SystemTime>) -
     if !old_md.is_none() {
         nmd = old_md.unwrap();
     }
-    let mut dur = std::time::Duration::new(td.s_diff as u64, td.ns_diff as u32);
-    if td.s_diff < 0 {
-        dur = std::time::Duration::new((td.s_diff * -1) as u64, (td.ns_diff * -1) as u32);
-        nmd.checked_sub(dur);
+    // `ns_diff` holds the whole difference in nanoseconds, not just the sub-second part
+    let dur = std::time::Duration::from_nanos(td.ns_diff.unsigned_abs() as u64);
+    if td.ns_diff < 0 {
+        nmd = nmd.checked_sub(dur).unwrap_or(nmd);
     } else {
-        nmd.checked_add(dur);
+        nmd = nmd.checked_add(dur).unwrap_or(nmd);
     }
     return Some(nmd)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_file_diff_to_entry_shrinks_file() {
+        let mut ent = walk::FileEntry { bn: std::ffi::OsString::from("f"), sz: 100_000, alloc_sz: 102_400, ..Default::default() };
+        let d = FileEntryDiff { bn: std::ffi::OsString::from("f"), sz: -60_000, alloc_sz: -61_440, ..Default::default() };
+        merge_file_diff_to_entry(&mut ent, d);
+        assert_eq!(ent.sz, 40_000);
+        assert_eq!(ent.alloc_sz, 40_960);
+
+        // And grows again
+        let d = FileEntryDiff { bn: std::ffi::OsString::from("f"), sz: 5_000, alloc_sz: 8_192, ..Default::default() };
+        merge_file_diff_to_entry(&mut ent, d);
+        assert_eq!(ent.sz, 45_000);
+        assert_eq!(ent.alloc_sz, 49_152);
+    }
+}
@@ -0,0 +1,71 @@
+use std::{fs::exists, path::PathBuf, time::SystemTime};
+use crate::{save::{open_save_file, read_diff_file, read_index_file}, utility};
+
+// Prints every store in `dir`: its roots, when it was first scanned, the diffs since and the size of its files. Stores
+// that can't be read are listed with the error, so the rest are still shown
+pub fn list_stores(dir: &PathBuf, is_su: bool) -> std::io::Result<usize> {
+    let index = read_index_file(dir)?;
+    let mut keys: Vec<String> = vec![];
+    for ent in std::fs::read_dir(dir)? {
+        let name = ent?.file_name().to_string_lossy().to_string();
+        if let Some(key) = name.strip_suffix("_initial") {
+            keys.push(key.to_string());
+        }
+    }
+    keys.sort();
+
+    let su_note = if is_su {" (su)"} else {""};
+    for key in &keys {
+        let path_to_initial = dir.join(format!("{}_initial", key));
+        let path_to_diff = dir.join(format!("{}_diffs", key));
+        let maybe_header = open_save_file(&path_to_initial).map(|r| r.header);
+        if maybe_header.is_err() {
+            println!("ERR: store {}{}: {}", key, su_note, maybe_header.err().unwrap());
+            continue;
+        }
+        let header = maybe_header.unwrap();
+        let maybe_profile = index.stores.iter().find(|e| &e.key == key).and_then(|e| e.maybe_profile.clone());
+        match maybe_profile {
+            Some(name) => {
+                println!("Profile '{}'{}: {:?}", name, su_note, header.roots);
+            }
+            None => {
+                println!("Root {:?}{}:", header.roots[0], su_note);
+            }
+        }
+        println!("    store key:     {}", key);
+        println!("    initial scan:  {} ({}, {})", get_local_datetime(header.created), get_file_size(&path_to_initial), header.compression);
+
+        if !exists(&path_to_diff)? {
+            println!("    diffs:         none");
+            continue;
+        }
+        let maybe_diff_file = read_diff_file(&path_to_diff);
+        if maybe_diff_file.is_err() {
+            println!("    diffs:         ERR: {}", maybe_diff_file.err().unwrap());
+            continue;
+        }
+        let diff_file = maybe_diff_file.unwrap();
+        // The cached merged diff is the last entry, it's not a scan
+        let mut timestamps = &diff_file.timestamps[..];
+        if diff_file.has_merged_diff && timestamps.len() > 0 {
+            timestamps = &timestamps[..timestamps.len() - 1];
+        }
+        let merged_note = if diff_file.has_merged_diff {", merged diff cached"} else {""};
+        println!("    diffs:         {} entries{} ({})", timestamps.len(), merged_note, get_file_size(&path_to_diff));
+        for t in timestamps {
+            println!("        {}", get_local_datetime(*t));
+        }
+    }
+    return Ok(keys.len());
+}
+
+// Same format as `--start-report` and `--end-report` take
+fn get_local_datetime(t: SystemTime) -> String {
+    return chrono::DateTime::<chrono::Local>::from(t).format("%Y-%m-%dT%H:%M:%S").to_string();
+}
+
+fn get_file_size(p: &PathBuf) -> String {
+    let sz = std::fs::metadata(p).map(|md| md.len()).unwrap_or(0);
+    return utility::get_shorthand_file_size(sz as i64).trim_start_matches('+').to_string();
+}
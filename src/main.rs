@@ -4,27 +4,46 @@ mod scan;
 mod diff;
 mod report;
 mod utility;
+mod progress;
+mod checkpoint;
+mod spill;
+mod throttle;
+mod list;
 
 extern crate libc;
 
-const DEFAULT_NUM_THREADS: usize    = 84;
 const DEFAULT_FD_LIMIT: usize       = 2048;
 const DEFAULT_MIN_DIFF_BYTES: usize = 50 * utility::MEGABYTE;
+const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
 
 struct Config {
-    num_threads: usize,
+    // `None` picks the number of threads from the CPU count and storage type of the target path
+    num_threads: Option<usize>,
     file_dir_limit: usize,
     min_diff_bytes: usize,
     show_perf_info: bool,
     show_moved_files: bool,
     cache_merged_diff: bool,
     maybe_start_report_time: Option<std::time::SystemTime>,
-    maybe_end_report_time: Option<std::time::SystemTime>
+    maybe_end_report_time: Option<std::time::SystemTime>,
+    scan_options: scan::ScanOptions,
+    use_allocated_size: bool,
+    show_symlinks: bool,
+    show_specials: bool,
+    by_owner: bool,
+    incremental: bool,
+    progress_mode: progress::ProgressMode,
+    resume: bool,
+    maybe_memory_limit: Option<usize>,
+    maybe_profile: Option<String>,
+    priority: throttle::Priority,
+    maybe_iops_limit: Option<u64>,
+    compression: save::Compression,
 }
 
 fn main() {
     let mut cfg = Config {
-        num_threads:             DEFAULT_NUM_THREADS,
+        num_threads:             None,
         file_dir_limit:          DEFAULT_FD_LIMIT,
         min_diff_bytes:          DEFAULT_MIN_DIFF_BYTES,
         show_perf_info:          false,
@@ -32,6 +51,19 @@ fn main() {
         cache_merged_diff:       false,
         maybe_start_report_time: None,
         maybe_end_report_time:   None,
+        scan_options:            scan::ScanOptions::default(),
+        use_allocated_size:      false,
+        show_symlinks:           false,
+        show_specials:           false,
+        by_owner:                false,
+        incremental:             false,
+        progress_mode:           progress::ProgressMode::Auto,
+        resume:                  false,
+        maybe_memory_limit:      None,
+        maybe_profile:           None,
+        priority:                throttle::Priority::default(),
+        maybe_iops_limit:        None,
+        compression:             save::Compression::Zstd(DEFAULT_COMPRESSION_LEVEL),
     };
 
     let args: Vec<String> = std::env::args().collect();
@@ -42,7 +74,15 @@ fn main() {
 
     let is_root  = unsafe { libc::geteuid() == 0 };
     let cmd      = args[1].as_str();
-    let params: Vec<_> = args.iter().skip(2).collect();
+    // `--opt=value` is the same as `--opt value`
+    let split_args: Vec<String> = args.iter().skip(2).flat_map(|a| {
+        if a.starts_with("--") && a.contains('=') {
+            let (opt, val) = a.split_once('=').unwrap();
+            return vec![opt.to_string(), val.to_string()];
+        }
+        return vec![a.clone()];
+    }).collect();
+    let params: Vec<_> = split_args.iter().collect();
     match cmd {
         "scan" => {
             let mut is_root_msg = "NOT ";
@@ -51,12 +91,7 @@ fn main() {
             }
             println!("{}running as SUDO", is_root_msg);
 
-            if params.len() < 2 {
-                eprintln!("insufficient arguments for `scan`, expected at least [INPUT SCAN PATH] and [OUTPUT SCAN FILE PATH]");
-                return;
-            }
             let optional_args: Vec<_> = params.iter().collect();
-            let num_args = optional_args.len();
 
             // Get optional params
             let arg_eval_res = eval_optional_args("scan", optional_args, &mut cfg);
@@ -64,18 +99,14 @@ fn main() {
                 eprintln!("invalid argument provided: {}", arg_eval_res.err().unwrap());
                 return;
             }
-
-            // Get input scan path
-            let maybe_target_str = params[num_args - 2];
-            let maybe_target_pb = validate_get_pathbuf(maybe_target_str);
-            if maybe_target_pb.is_err() {
-                eprintln!("invalid target path provided: {}", maybe_target_pb.err().unwrap());
+            let paths = &params[arg_eval_res.unwrap()..];
+            if paths.len() < 1 || (cfg.maybe_profile.is_none() && paths.len() != 2) {
+                eprintln!("insufficient arguments for `scan`, expected [INPUT SCAN PATH] and [OUTPUT SCAN FILE PATH], or --profile <name> [INPUT SCAN PATH...] [OUTPUT SCAN FILE PATH]");
                 return;
             }
-            let target_pb = maybe_target_pb.unwrap();
 
             // Get scan output path
-            let maybe_output_pb = validate_get_pathbuf(params[num_args - 1]);
+            let maybe_output_pb = validate_get_pathbuf(paths[paths.len() - 1]);
             if maybe_output_pb.is_err() {
                 eprintln!("invalid output scan path provided: {}", maybe_output_pb.err().unwrap());
                 return;
@@ -101,14 +132,41 @@ fn main() {
                 output_pb = su_path;
             }
 
+            // Get input scan path(s)
+            let maybe_target = get_scan_target(&paths[..paths.len() - 1], &output_pb, &cfg);
+            if maybe_target.is_err() {
+                eprintln!("invalid target path provided: {}", maybe_target.err().unwrap());
+                return;
+            }
+            let target = maybe_target.unwrap();
+
+            let maybe_pool = utility::build_thread_pool(cfg.num_threads, &target.roots[0], cfg.priority);
+            if maybe_pool.is_err() {
+                eprintln!("failed to start scan threads: {}", maybe_pool.err().unwrap());
+                return;
+            }
+            let pool = maybe_pool.unwrap();
+
             let bef = std::time::Instant::now();
-            let res = scan::scan(target_pb, output_pb, cfg.min_diff_bytes, cfg.num_threads, cfg.file_dir_limit, cfg.cache_merged_diff);
+            let flags = scan::ScanFlags {
+                min_diff_bytes: cfg.min_diff_bytes,
+                thread_add_dir_limit: cfg.file_dir_limit,
+                cache_merged_diffs: cfg.cache_merged_diff,
+                incremental: cfg.incremental,
+                progress_mode: cfg.progress_mode,
+                resume: cfg.resume,
+                maybe_memory_limit: cfg.maybe_memory_limit,
+                maybe_iops_limit: cfg.maybe_iops_limit,
+                compression: cfg.compression,
+            };
+            let res = pool.install(|| scan::scan(&target, output_pb, cfg.scan_options.clone(), flags));
             let took = bef.elapsed();
             match res {
-                Ok((num_files, num_dirs)) => {
+                Ok((num_files, num_dirs, walk_errors)) => {
                     if cfg.show_perf_info {
                         println!("Scanned {} files, {} directories in: {}ms", num_files, num_dirs, took.as_millis())
                     }
+                    scan::print_walk_error_summary(&walk_errors);
                 }
                 Err(e) => {
                     eprintln!("error occured while scanning: {}", e);
@@ -116,12 +174,7 @@ fn main() {
             }
         }
         "report" => {
-            if params.len() < 2 {
-                eprintln!("insufficient arguments for `report`, expected at least [INPUT SCAN PATH] and [OUTPUT SCAN FILE PATH]");
-                return;
-            }
             let optional_args: Vec<_> = params.iter().collect();
-            let num_args = optional_args.len();
 
             // Get optional params
             let arg_eval_res = eval_optional_args("report", optional_args, &mut cfg);
@@ -129,18 +182,14 @@ fn main() {
                 eprintln!("invalid argument provided: {}", arg_eval_res.err().unwrap());
                 return;
             }
-            
-            // Get input scan path
-            let maybe_target_str = params[num_args - 2];
-            let maybe_target_pb = validate_get_pathbuf(maybe_target_str);
-            if maybe_target_pb.is_err() {
-                eprintln!("invalid target path provided: {}", maybe_target_pb.err().unwrap());
+            let paths = &params[arg_eval_res.unwrap()..];
+            if paths.len() < 1 || (cfg.maybe_profile.is_none() && paths.len() != 2) {
+                eprintln!("insufficient arguments for `report`, expected [INPUT SCAN PATH] and [OUTPUT SCAN FILE PATH], or --profile <name> [OUTPUT SCAN FILE PATH]");
                 return;
             }
-            let target_pb = maybe_target_pb.unwrap();
 
             // Get scan output path
-            let maybe_output_pb = validate_get_pathbuf(params[num_args - 1]);
+            let maybe_output_pb = validate_get_pathbuf(paths[paths.len() - 1]);
             if maybe_output_pb.is_err() {
                 eprintln!("invalid output scan path provided: {}", maybe_output_pb.err().unwrap());
                 return;
@@ -162,8 +211,23 @@ fn main() {
             if is_root {
                 output_pb = su_path;
             }
+
+            // Get input scan path(s)
+            let maybe_target = get_scan_target(&paths[..paths.len() - 1], &output_pb, &cfg);
+            if maybe_target.is_err() {
+                eprintln!("invalid target path provided: {}", maybe_target.err().unwrap());
+                return;
+            }
+            let target = maybe_target.unwrap();
             
-            let res = report::report_changes(target_pb, output_pb, cfg);
+            let maybe_pool = utility::build_thread_pool(cfg.num_threads, &target.roots[0], cfg.priority);
+            if maybe_pool.is_err() {
+                eprintln!("failed to start report threads: {}", maybe_pool.err().unwrap());
+                return;
+            }
+            let pool = maybe_pool.unwrap();
+
+            let res = pool.install(|| report::report_changes(&target, output_pb, cfg));
             match res {
                 Ok(()) => {}
                 Err(e) => {
@@ -171,11 +235,74 @@ fn main() {
                 }
             }
         }
+        "list" => {
+            if params.len() != 1 {
+                eprintln!("insufficient arguments for `list`, expected [OUTPUT SCAN FILE PATH]");
+                return;
+            }
+            let maybe_output_pb = validate_get_pathbuf(params[0]);
+            if maybe_output_pb.is_err() {
+                eprintln!("invalid output scan path provided: {}", maybe_output_pb.err().unwrap());
+                return;
+            }
+            let output_pb = maybe_output_pb.unwrap();
+
+            let mut num_stores = 0;
+            for (dir, is_su) in [(output_pb.clone(), false), (output_pb.join("su"), true)] {
+                if !dir.is_dir() {
+                    continue;
+                }
+                match list::list_stores(&dir, is_su) {
+                    Ok(n) => {
+                        num_stores += n;
+                    }
+                    Err(e) => {
+                        eprintln!("error occured while listing {:?}: {}", dir, e);
+                        return;
+                    }
+                }
+            }
+            if num_stores == 0 {
+                println!("no scans found in {:?}", output_pb);
+            }
+        }
+        "migrate" => {
+            if params.len() != 1 {
+                eprintln!("insufficient arguments for `migrate`, expected [OUTPUT SCAN FILE PATH]");
+                return;
+            }
+            let maybe_output_pb = validate_get_pathbuf(params[0]);
+            if maybe_output_pb.is_err() {
+                eprintln!("invalid output scan path provided: {}", maybe_output_pb.err().unwrap());
+                return;
+            }
+            let output_pb = maybe_output_pb.unwrap();
+
+            // Scans run as root are saved in the `su` folder
+            let mut num_migrated = 0;
+            for dir in [output_pb.clone(), output_pb.join("su")] {
+                if !dir.is_dir() {
+                    continue;
+                }
+                match save::migrate_store(&dir) {
+                    Ok(num_changed) => {
+                        num_migrated += num_changed;
+                    }
+                    Err(e) => {
+                        eprintln!("error occured while migrating: {}", e);
+                        return;
+                    }
+                }
+            }
+            if num_migrated == 0 {
+                println!("all saves are already in save format {} with current store keys", save::SAVE_FORMAT_VERSION);
+            }
+        }
         "--help" => {
             print_help_text();
         }
         _ => {
-            eprintln!("invalid command '{}' provided, must be one of: {}", cmd, vec!["scan", "report", "--help"].join(", "));
+            eprintln!("invalid command '{}' provided, must be one of: {}", cmd, ["scan", "report", "list", "migrate", "--help"].join(", "));
             return;
         }
     }
@@ -190,14 +317,45 @@ fn validate_get_pathbuf(p: &String) -> std::io::Result<std::path::PathBuf> {
     return Ok(std::path::PathBuf::from(&p));
 }
 
-fn eval_optional_args(cmd: &str, args: Vec<&&String>, cfg: &mut Config) -> std::io::Result<()> {    
+// A single target path, or the roots of `--profile` (which can be left out once the profile exists)
+fn get_scan_target(target_paths: &[&String], output_pb: &std::path::PathBuf, cfg: &Config) -> std::io::Result<scan::ScanTarget> {
+    let mut roots = Vec::with_capacity(target_paths.len());
+    for p in target_paths {
+        // Stores are keyed by the canonical path, so `./dir`, `dir/` and `/abs/dir` share one
+        roots.push(std::fs::canonicalize(validate_get_pathbuf(p)?)?);
+    }
+    match &cfg.maybe_profile {
+        Some(name) => {
+            return scan::ScanTarget::from_profile(output_pb, name, roots);
+        }
+        None => {
+            return Ok(scan::ScanTarget::new(roots.pop().unwrap()));
+        }
+    }
+}
+
+fn parse_num_threads(a: &String) -> std::io::Result<Option<usize>> {
+    if a == "auto" {
+        return Ok(None);
+    }
+    let maybe_threads: Result<usize, std::num::ParseIntError> = a.parse();
+    if maybe_threads.is_err() || maybe_threads.clone().unwrap() < 1 {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid thread argument, must be 'auto' or at least 1"));
+    }
+    return Ok(Some(maybe_threads.unwrap()));
+}
+
+// Returns the index of the first path argument, they follow the options
+fn eval_optional_args(cmd: &str, args: Vec<&&String>, cfg: &mut Config) -> std::io::Result<usize> {    
     let mut i = 0;
-    let valid_command_options = vec!["-p", "-md", "-t", "-fdl", "-mvs", "--start-report", "--end-report", "--cache-merged-diff"];
+    let valid_command_options = vec!["-p", "-md", "-t", "-fdl", "-mvs", "--start-report", "--end-report", "--cache-merged-diff", "--exclude", "--include", "--one-file-system", "--hash", "--incremental", "--usage", "--symlinks", "--specials", "--progress", "--resume", "--memory-limit", "--max-depth", "--profile", "--by", "--nice", "--ionice", "--iops-limit", "--stat-rate-limit", "--fs-types", "--compression-level"];
     let local_tz_offset_secs = chrono::Local::now().offset().local_minus_utc();
     while i < args.len() {
-        let before_directory_args = i < args.len() - 2;
         let a = args[i].as_str();
-        if before_directory_args && !valid_command_options.contains(&a) {
+        if !a.starts_with('-') {
+            break;
+        }
+        if !valid_command_options.contains(&a) {
             let valid_params: Vec<_> = valid_command_options.clone().into_iter().collect();
             return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("invalid parameter '{}' provided for {} command, must be one of: {}", a, cmd, valid_params.join(", "))));
         }
@@ -213,6 +371,15 @@ fn eval_optional_args(cmd: &str, args: Vec<&&String>, cfg: &mut Config) -> std::
                     "--cache-merged-diff" => {
                         cfg.cache_merged_diff = true;
                     }
+                    "--one-file-system" => {
+                        cfg.scan_options.one_file_system = true;
+                    }
+                    "--resume" => {
+                        cfg.resume = true;
+                    }
+                    "--incremental" => {
+                        cfg.incremental = true;
+                    }
                     _ => {is_no_val_opt = false;}
                 }
                 if is_no_val_opt {
@@ -239,11 +406,7 @@ fn eval_optional_args(cmd: &str, args: Vec<&&String>, cfg: &mut Config) -> std::
                         }
                     }
                     "-t" => {
-                        let maybe_threads: Result<usize, std::num::ParseIntError> = args[i].parse();
-                        if maybe_threads.is_err() || maybe_threads.clone().unwrap() < 2 {
-                            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid thread argument, must be at least 2"));
-                        }
-                        cfg.num_threads = maybe_threads.unwrap();
+                        cfg.num_threads = parse_num_threads(args[i])?;
                     }
                     "-fdl" => {
                         let maybe_file_dir_limit: Result<usize, std::num::ParseIntError> = args[i].parse();
@@ -252,10 +415,103 @@ fn eval_optional_args(cmd: &str, args: Vec<&&String>, cfg: &mut Config) -> std::
                         }
                         cfg.file_dir_limit = maybe_file_dir_limit.unwrap();
                     }
-                    _ => {
-                        if before_directory_args {
-                            return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("unimplemented parameter: {}, for command: {}", a, cmd)));
+                    "--memory-limit" => {
+                        let maybe_memory_limit = utility::get_bytes_from_arg(args[i]);
+                        if maybe_memory_limit.is_err() {
+                            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("invalid memory limit argument, {}", maybe_memory_limit.err().unwrap())));
+                        }
+                        cfg.maybe_memory_limit = Some(maybe_memory_limit.unwrap());
+                    }
+                    "--compression-level" => {
+                        let maybe_level: Result<i32, std::num::ParseIntError> = args[i].parse();
+                        if maybe_level.is_err() || !(0..=22).contains(maybe_level.as_ref().unwrap()) {
+                            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid compression level argument, must be an integer from 0 to 22"));
+                        }
+                        cfg.compression = save::Compression::Zstd(maybe_level.clone().unwrap());
+                        if maybe_level.unwrap() == 0 {
+                            cfg.compression = save::Compression::None;
+                        }
+                    }
+                    "--fs-types" => {
+                        cfg.scan_options.set_skip_fs_types(args[i])?;
+                    }
+                    "--nice" => {
+                        let maybe_nice: Result<i32, std::num::ParseIntError> = args[i].parse();
+                        if maybe_nice.is_err() || !(-20..=19).contains(maybe_nice.as_ref().unwrap()) {
+                            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid nice argument, must be an integer from -20 to 19"));
+                        }
+                        cfg.priority.maybe_nice = Some(maybe_nice.unwrap());
+                    }
+                    "--ionice" => {
+                        match args[i].as_str() {
+                            "idle" => {
+                                cfg.priority.maybe_io_class = Some(throttle::IoClass::Idle);
+                            }
+                            "best-effort" => {
+                                cfg.priority.maybe_io_class = Some(throttle::IoClass::BestEffort);
+                            }
+                            _ => {
+                                return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid ionice argument, must be one of: idle, best-effort"));
+                            }
+                        }
+                    }
+                    "--iops-limit" | "--stat-rate-limit" => {
+                        let maybe_iops_limit: Result<u64, std::num::ParseIntError> = args[i].parse();
+                        if maybe_iops_limit.is_err() || maybe_iops_limit.clone().unwrap() < 1 {
+                            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid iops limit argument, must be a positive integer"));
+                        }
+                        cfg.maybe_iops_limit = Some(maybe_iops_limit.unwrap());
+                    }
+                    "--max-depth" => {
+                        let maybe_max_depth: Result<usize, std::num::ParseIntError> = args[i].parse();
+                        if maybe_max_depth.is_err() {
+                            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid max depth argument, must be a non-negative integer"));
                         }
+                        cfg.scan_options.max_depth = Some(maybe_max_depth.unwrap());
+                    }
+                    "--profile" => {
+                        cfg.maybe_profile = Some(args[i].to_string());
+                    }
+                    "--exclude" => {
+                        cfg.scan_options.exclude.push(args[i].to_string());
+                    }
+                    "--include" => {
+                        cfg.scan_options.include.push(args[i].to_string());
+                    }
+                    "--progress" => {
+                        match args[i].as_str() {
+                            "auto" => {
+                                cfg.progress_mode = progress::ProgressMode::Auto;
+                            }
+                            "json" => {
+                                cfg.progress_mode = progress::ProgressMode::Json;
+                            }
+                            "none" => {
+                                cfg.progress_mode = progress::ProgressMode::None;
+                            }
+                            _ => {
+                                return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid progress argument, must be one of: auto, json, none"));
+                            }
+                        }
+                    }
+                    "--hash" => {
+                        match args[i].as_str() {
+                            "none" => {
+                                cfg.scan_options.hash = walk::HashMode::None;
+                            }
+                            "quick" => {
+                                cfg.scan_options.hash = walk::HashMode::Quick;
+                            }
+                            "full" => {
+                                cfg.scan_options.hash = walk::HashMode::Full;
+                            }
+                            _ => {
+                                return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid hash argument, must be one of: none, quick, full"));
+                            }
+                        }
+                    }
+                    _ => {
+                        return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("unimplemented parameter: {}, for command: {}", a, cmd)));
                     }
                 }
             }
@@ -266,6 +522,12 @@ fn eval_optional_args(cmd: &str, args: Vec<&&String>, cfg: &mut Config) -> std::
                     "-mvs" => {
                         cfg.show_moved_files = true;
                     }
+                    "--symlinks" => {
+                        cfg.show_symlinks = true;
+                    }
+                    "--specials" => {
+                        cfg.show_specials = true;
+                    }
                     _ => {is_no_val_opt = false;}
                 }
                 if is_no_val_opt {
@@ -278,6 +540,22 @@ fn eval_optional_args(cmd: &str, args: Vec<&&String>, cfg: &mut Config) -> std::
                     return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("missing additional argument for '{}' flag", a)));
                 }
                 match a {
+                    "-t" => {
+                        cfg.num_threads = parse_num_threads(args[i])?;
+                    }
+                    "--profile" => {
+                        cfg.maybe_profile = Some(args[i].to_string());
+                    }
+                    "--by" => {
+                        match args[i].as_str() {
+                            "owner" => {
+                                cfg.by_owner = true;
+                            }
+                            _ => {
+                                return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid by argument, must be one of: owner"));
+                            }
+                        }
+                    }
                     "--start-report" => {
                         let maybe_start_report: Result<String, std::string::ParseError> = args[i].parse();
                         if maybe_start_report.is_err() {
@@ -300,11 +578,22 @@ fn eval_optional_args(cmd: &str, args: Vec<&&String>, cfg: &mut Config) -> std::
                         }
                         cfg.maybe_end_report_time = Some(std::time::SystemTime::from(maybe_datetime.unwrap()));
                     }
-                    _ => {
-                        if before_directory_args {
-                            return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("unimplemented parameter: {}, for command: {}", a, cmd)));
+                    "--usage" => {
+                        match args[i].as_str() {
+                            "apparent" => {
+                                cfg.use_allocated_size = false;
+                            }
+                            "allocated" => {
+                                cfg.use_allocated_size = true;
+                            }
+                            _ => {
+                                return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid usage argument, must be one of: apparent, allocated"));
+                            }
                         }
                     }
+                    _ => {
+                        return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("unimplemented parameter: {}, for command: {}", a, cmd)));
+                    }
                 }
             }
             _ => {
@@ -315,14 +604,18 @@ fn eval_optional_args(cmd: &str, args: Vec<&&String>, cfg: &mut Config) -> std::
         i += 1;
     }
  
-    Ok(())    
+    Ok(i)    
 }
 
 fn print_help_text() {
     println!("Storage eye, identifies changes in disk usage and moved files in a target directory through scanning over time
 
-Usage: seye scan [options] [pattern] [path]
-       seye report [options] [pattern] [path]
+Usage: {bin} scan [options] [pattern] [path]
+       {bin} scan [options] --profile <name> [pattern...] [path]
+       {bin} report [options] [pattern] [path]
+       {bin} report [options] --profile <name> [path]
+       {bin} list [path]
+       {bin} migrate [path]
 Scan Arguments:
     --help                                  Prints help
     --version                               Prints version
@@ -330,15 +623,61 @@ Scan Arguments:
     -p                                      Show performance statistics after scan
     -md                   (default:  50MB)  Specify the minimum size difference to include in diffs, can specify one of: n, nK, nM or nG, e.g. 1M
     
-    -t   <num>            (default:    {})  Specify the number of threads, MUST BE >= 2
-    -fdl <num>            (default:  {})  Specify the maximum 'files + dirs' to traverse before returning results from each thread
+    -t   <num>            (default:  auto)  Specify the number of threads used by the scan (walk, sort and diff) or report, 'auto' picks it from
+                                            the CPU count and whether the target is on a rotational disk, an SSD or not on a local disk
+    -fdl <num>            (default:  {})  Specify the maximum 'files + dirs' a task traverses before splitting off its remaining dirs
 
     --cache-merged-diff                     Keep a copy of all the diffs merged together in the `diffs` file (increases performance and `diffs` size)
+
+    --exclude <pattern>                     Don't walk or record paths matching the pattern, can be repeated (e.g. node_modules, /home/*/.cache, re:\\.bak$)
+    --include <pattern>                     Only record files matching the pattern, directories are still walked, can be repeated
+                                            Patterns are globs, or regexes when prefixed with 're:'. Globs without a '/' match the basename,
+                                            all other patterns match the full path. Patterns are stored with the initial scan and must match on later scans
+    --incremental                           Reuse directories whose mtime and ctime haven't changed since the last scan, without reading them
+                                            (faster, but misses files modified in place in those directories)
+    --max-depth <n>                         Only record directories down to n levels below the target path (0 is just the target), the
+                                            files and sizes of deeper directories are added to their ancestor at depth n. Stored with the initial scan
+    --hash <mode>         (default:  none)  Hash file contents to detect changes that keep the same size, one of: none, quick (first and last 8K), full
+    --resume                                Continue the walk of a scan that was interrupted (Ctrl-C, SIGTERM, crash) from its checkpoint,
+                                            starts a new scan if there's no checkpoint. Interrupted scans never save a diff. The walk is
+                                            saved every 30s (walks that finish sooner aren't saved), a second Ctrl-C exits without saving
+    --memory-limit <n>                      Keep at most about n (e.g. 512M, 2G) of scanned entries in memory, the rest is sorted into runs
+                                            on disk in the output directory and merged back to diff. Can't be used with --incremental
+    --progress <mode>     (default:  auto)  Show walk progress on stderr, one of: auto (a progress line when stderr is a terminal),
+                                            json (NDJSON events, also as '--progress=json'), none
+    --profile <name>                        Scan several target paths together under one name, moves between them are detected as moves.
+                                            The paths are stored on the profile's first scan and can be left out after that
+    --compression-level <n>                 zstd level of the saved scan and diffs (default: {}), from 1 (fastest) to 22 (smallest),
+                                            0 saves them uncompressed. Each file records its own, so it can change between scans
+    --nice <n>                              Run the scan with a nice value from -20 to 19 (e.g. 19 to only use otherwise idle CPU)
+    --ionice <class>                        Run the scan in an IO scheduling class, one of: idle (only uses the disk when nothing else
+                                            does), best-effort (the lowest priority of the default class)
+    --iops-limit <n>                        Make at most n 'read_dir' and 'metadata' calls per second, across all threads.
+                                            Also as '--stat-rate-limit'
+    --fs-types <list>                       Filesystem types whose mounts aren't walked (recorded as 'not traversed'), comma separated.
+                                            Defaults to pseudo filesystems like proc, sysfs, devtmpfs and cgroup2. Plain types replace the
+                                            defaults, '+type' and '-type' add to or remove from them (e.g. +tmpfs,+overlay), 'none' walks
+                                            every filesystem. Stored with the initial scan
+    --one-file-system                       Don't descend into directories on a different filesystem to the target path, they're recorded as 'not traversed'
 Report Arguments:
     -mvs                                    Show moved files in the report output (even though the size of a MOVE is 0B)
+    --symlinks                              Show added, removed and retargeted symlinks, and list broken symlinks under the target path
+    --specials                              Show added and removed special files (FIFOs, sockets, device nodes) and list all of them,
+                                            they're never counted as files or in sizes
+    --by owner                              Show the size and change of each user and group instead of each directory, names are
+                                            read from /etc/passwd and /etc/group
+    --profile <name>                        Report on a profile's scans, with the totals of each of its target paths
+    -t   <num>            (default:  auto)  Specify the number of threads used to combine and sort diffs, same values as for scan
 
     --start-report        (default: first)  Specifies the earliest diff that will be included in the report (format: 2025-05-05T10:00:00, uses system timezone)
     --end-report          (default:  last)  Specifies the latest diff that will be included in the report (format: 2025-05-05T10:00:00, uses system timezone)
+    --usage <kind>     (default: apparent) Which size to report, one of: apparent (file sizes), allocated (disk blocks actually used)
+List:
+    Shows the roots scanned into an output directory (and its 'su' folder), with the time of each scan and the size of
+    their files
+Migrate:
+    Upgrades the saved scans in an output directory to the save format of this version, and renames stores keyed by an
+    older version, in place
 ", 
-    DEFAULT_NUM_THREADS, DEFAULT_FD_LIMIT);
+    DEFAULT_FD_LIMIT, DEFAULT_COMPRESSION_LEVEL, bin = env!("CARGO_PKG_NAME"));
 }
\ No newline at end of file
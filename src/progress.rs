@@ -0,0 +1,76 @@
+use std::{io::{IsTerminal, Write}, sync::{atomic::{AtomicU64, AtomicUsize, Ordering}, mpsc::{Receiver, RecvTimeoutError}}, time::{Duration, Instant}};
+
+const REFRESH_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum ProgressMode {
+    // A progress line on stderr, only when stderr is a TTY
+    #[default]
+    Auto,
+    // One NDJSON event per refresh on stderr, for job runners
+    Json,
+    None,
+}
+
+// Counters shared by all walk threads
+#[derive(Default)]
+pub struct Progress {
+    pub dirs: AtomicUsize,
+    pub files: AtomicUsize,
+    pub bytes: AtomicU64,
+    // Directories found but not walked yet
+    pub queued: AtomicUsize,
+}
+impl Progress {
+    pub fn add_queued(&self, delta: isize) {
+        if delta < 0 {
+            self.queued.fetch_sub(delta.unsigned_abs(), Ordering::Relaxed);
+        } else {
+            self.queued.fetch_add(delta as usize, Ordering::Relaxed);
+        }
+    }
+}
+
+// Prints progress every `REFRESH_INTERVAL` until `stop` is signalled or dropped
+pub fn report_until_stopped(p: &Progress, mode: ProgressMode, stop: Receiver<()>) {
+    let is_tty = std::io::stderr().is_terminal();
+    if mode == ProgressMode::None || (mode == ProgressMode::Auto && !is_tty) {
+        return;
+    }
+
+    let start = Instant::now();
+    loop {
+        let done = stop.recv_timeout(REFRESH_INTERVAL) != Err(RecvTimeoutError::Timeout);
+        print_progress(p, mode, start.elapsed(), done);
+        if done {
+            return;
+        }
+    }
+}
+
+fn print_progress(p: &Progress, mode: ProgressMode, elapsed: Duration, done: bool) {
+    let dirs = p.dirs.load(Ordering::Relaxed);
+    let files = p.files.load(Ordering::Relaxed);
+    let bytes = p.bytes.load(Ordering::Relaxed);
+    let queued = p.queued.load(Ordering::Relaxed);
+    let secs = elapsed.as_secs_f64().max(0.001);
+    let files_per_sec = (files as f64 / secs) as u64;
+    let dirs_per_sec = (dirs as f64 / secs) as u64;
+
+    let mut stderr = std::io::stderr().lock();
+    if mode == ProgressMode::Json {
+        let event = if done {"done"} else {"progress"};
+        _ = writeln!(stderr, "{{\"event\":\"{}\",\"elapsed_ms\":{},\"dirs\":{},\"files\":{},\"bytes\":{},\"queued\":{},\"dirs_per_sec\":{},\"files_per_sec\":{}}}",
+            event, elapsed.as_millis(), dirs, files, bytes, queued, dirs_per_sec, files_per_sec);
+        return;
+    }
+
+    // Rewrite the same line, then leave the final one in place
+    let bytes_str = crate::utility::get_shorthand_file_size(bytes as i64);
+    _ = write!(stderr, "\r\x1b[K{} dirs, {} files, {} seen, {} queued ({} files/s)",
+        dirs, files, bytes_str.trim_start_matches('+'), queued, files_per_sec);
+    if done {
+        _ = writeln!(stderr);
+    }
+    _ = stderr.flush();
+}
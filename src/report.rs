@@ -1,8 +1,8 @@
-use std::{cmp::Ordering, fs::exists, io::Error, path::PathBuf};
-use crate::{diff::{get_diff_type_shorthand, DiffEntry, ADD_DT_IDX, MOD_DT_IDX, REM_DT_IDX}, save::{self, read_diff_file, read_save_file}, scan::add_combined_diffs, utility, walk::CDirEntry, Config};
+use std::{cmp::Ordering, collections::HashMap, ffi::OsString, fs::exists, io::Error, path::PathBuf};
+use crate::{diff::{get_diff_type_shorthand, ignore_dir_entry, ignore_file_entry, CDirEntryDiff, DiffEntry, FileEntryDiff, ADD_DT_IDX, MOD_DT_IDX, NUM_DT, REM_DT_IDX}, save::{add_diffs, read_diff_file, read_save_file}, scan::{add_combined_diffs, apply_combined_diffs, get_num_diffs_before, ScanTarget}, utility, walk::{Attrs, CDirEntry, FileEntry}, Config};
 
-pub fn report_changes(target_path: PathBuf, output_path: PathBuf, cfg: Config) -> std::io::Result<()> {
-    let root_path_hash = save::get_hash_from_root_path(&target_path);
+pub fn report_changes(target: &ScanTarget, output_path: PathBuf, cfg: Config) -> std::io::Result<()> {
+    let root_path_hash = &target.key;
     let mut path_to_initial = output_path.clone();
     path_to_initial.push(format!("{}_initial", root_path_hash));
 
@@ -13,15 +13,28 @@ pub fn report_changes(target_path: PathBuf, output_path: PathBuf, cfg: Config) -
         return Err(std::io::Error::new(std::io::ErrorKind::Other, "No diffs found, run a scan first"))
     }
 
-    let full_scan_entries: Vec<CDirEntry>;
+    let mut full_scan_entries: Vec<CDirEntry>;
     let maybe_last_scan = read_save_file(path_to_initial);
     match maybe_last_scan {
-        Ok(entries) => {full_scan_entries = entries}
+        Ok(save_file) => {
+            if save_file.header.roots != target.roots {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("the saved scan is of {:?}, not {:?}", save_file.header.roots, target.roots)));
+            }
+            full_scan_entries = save_file.entries;
+        }
         Err(e) => {return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to read entries from file: {}", e)))}
     }
 
     let combined_diffs: DiffEntry;
     let diff_file = read_diff_file(&path_to_diff)?;
+    // Diffs from before `--start-report` are applied first, so the sizes at the start (and end) of the range include them
+    if cfg.maybe_start_report_time.is_some() {
+        let num_before = get_num_diffs_before(&diff_file, cfg.maybe_start_report_time.unwrap());
+        if num_before > 0 {
+            let mut diffs_before = add_diffs(&full_scan_entries, diff_file.entries[..num_before].to_vec());
+            apply_combined_diffs(&mut full_scan_entries, &mut diffs_before)?;
+        }
+    }
     let res: Result<DiffEntry, Error> = add_combined_diffs(&diff_file, &full_scan_entries, cfg.maybe_start_report_time, cfg.maybe_end_report_time);
     match res {
         Ok(ds) => {
@@ -38,8 +51,9 @@ pub fn report_changes(target_path: PathBuf, output_path: PathBuf, cfg: Config) -
     let last_add_idx: i32 = combined_diffs.diffs[ADD_DT_IDX].len() as i32 - 1;
     let last_rem_idx: i32 = last_add_idx + combined_diffs.diffs[REM_DT_IDX].len() as i32;
     let mut all_diffs: Vec<_> = combined_diffs.diffs.concat().into_iter().enumerate().collect();
+    let use_allocated = cfg.use_allocated_size;
     all_diffs.sort_by(|a, b| {
-        if (a.1.size_here + a.1.size_below) <= (b.1.size_here + b.1.size_below) {
+        if get_diff_size(&a.1, use_allocated) <= get_diff_size(&b.1, use_allocated) {
             return Ordering::Greater
         }
         return Ordering::Less
@@ -58,10 +72,12 @@ pub fn report_changes(target_path: PathBuf, output_path: PathBuf, cfg: Config) -
 
     let limit = all_diffs.len();
     let mut total: i64 = 0;
+    let mut unique_total: i64 = 0;
     let mut found_first_negative_diff = false;
     for i in 0..limit {
         let t = get_diff_type_shorthand(all_diffs[i].0);
-        if cfg.show_moved_files && !found_first_negative_diff && all_diffs[i].1.size_here + all_diffs[i].1.size_below < 0 {
+        let diff_size = get_diff_size(&all_diffs[i].1, use_allocated);
+        if cfg.show_moved_files && !found_first_negative_diff && diff_size < 0 {
             found_first_negative_diff = true;
             for kv in &combined_diffs.move_to_paths {
                 let from = kv.0;
@@ -69,13 +85,369 @@ pub fn report_changes(target_path: PathBuf, output_path: PathBuf, cfg: Config) -
                 println!("MOV: {:?} -> {:?} ({})", from, to, utility::get_shorthand_file_size(0));
             }
         }
-        if (all_diffs[i].1.size_here + all_diffs[i].1.size_below) == 0 {
+        // Same size rewrites are only visible with `scan --hash`
+        for f in &all_diffs[i].1.files[MOD_DT_IDX] {
+            if f.hash_changed && f.sz == 0 {
+                println!("CHG: {:?} (content changed, same size)", all_diffs[i].1.p.join(&f.bn));
+            }
+        }
+        // A new or removed hardlink only changes the unique size, it's not listed but still counted
+        unique_total += all_diffs[i].1.unique_size_here + all_diffs[i].1.unique_size_below;
+        if diff_size == 0 {
             continue;
         }
-        println!("{}: {:?} ({})", t, all_diffs[i].1.p, utility::get_shorthand_file_size(all_diffs[i].1.size_here + all_diffs[i].1.size_below));
-        total += all_diffs[i].1.size_here + all_diffs[i].1.size_below;
+        if !cfg.by_owner {
+            println!("{}: {:?} ({})", t, all_diffs[i].1.p, utility::get_shorthand_file_size(diff_size));
+        }
+        total += diff_size;
+    }
+
+    print_attrs_diffs(&combined_diffs);
+    if cfg.show_symlinks {
+        print_symlink_diffs(&combined_diffs);
+    }
+    if cfg.show_specials {
+        print_special_diffs(&combined_diffs);
+    }
+
+    let mut maybe_owner_totals = None;
+    if cfg.by_owner {
+        maybe_owner_totals = Some(OwnerTotals::from_entries(&full_scan_entries, use_allocated));
+    }
+
+    // List mount points that weren't traversed (and maybe broken symlinks and special files), as of the end of the report range
+    apply_combined_diffs(&mut full_scan_entries, &mut combined_diffs.clone())?;
+    for ent in &full_scan_entries {
+        if ent.not_traversed {
+            println!("SKP: {:?} (not traversed)", ent.p);
+        }
+        if cfg.show_specials {
+            for sp in &ent.specials {
+                println!("SPC: {:?} ({})", ent.p.join(&sp.bn), sp.kind);
+            }
+        }
+        if !cfg.show_symlinks {
+            continue;
+        }
+        for sl in &ent.symlinks {
+            if sl.dangling {
+                println!("BRK: {:?} -> {:?}", ent.p.join(&sl.bn), sl.target.clone().unwrap_or_default());
+            }
+        }
+    }
+    if maybe_owner_totals.is_some() {
+        let mut owner_totals = maybe_owner_totals.unwrap();
+        owner_totals.add_diffs(&combined_diffs, &full_scan_entries);
+        owner_totals.print();
+    }
+    if target.roots.len() > 1 {
+        print_root_totals(&target.roots, &full_scan_entries, &all_diffs, &combined_diffs.move_to_paths, use_allocated);
+    }
+    if use_allocated {
+        // Only the `*_here` props are kept up to date by diffs, so sum those
+        let alloc_size: i64 = full_scan_entries.iter().map(|ent| ent.alloc_here).sum();
+        println!("Total size is: {} allocated", utility::get_shorthand_file_size(alloc_size).trim_start_matches('+'));
+        println!("Total change is: {} allocated", utility::get_shorthand_file_size(total));
+        return Ok(());
     }
-    println!("Total change is: {}", utility::get_shorthand_file_size(total));
+
+    // Apparent sizes count every hard link, unique sizes count each inode once
+    let size: i64 = full_scan_entries.iter().map(|ent| ent.size_here).sum();
+    let unique_size: i64 = full_scan_entries.iter().map(|ent| ent.unique_size_here).sum();
+    println!("Total size is: {} apparent, {} unique", utility::get_shorthand_file_size(size).trim_start_matches('+'), utility::get_shorthand_file_size(unique_size).trim_start_matches('+'));
+    println!("Total change is: {} apparent, {} unique", utility::get_shorthand_file_size(total), utility::get_shorthand_file_size(unique_total));
 
     return Ok(());
-}
\ No newline at end of file
+}
+
+// Size and change of each root of a profile. A move between roots changes both, by the size of the moved directories
+fn print_root_totals(roots: &Vec<PathBuf>, entries: &Vec<CDirEntry>, all_diffs: &Vec<(usize, CDirEntryDiff)>, move_to_paths: &HashMap<PathBuf, PathBuf>, use_allocated: bool) {
+    let get_root_idx = |p: &PathBuf| roots.iter().position(|r| p.starts_with(r));
+    let mut totals: Vec<(i64, i64)> = vec![(0, 0); roots.len()];
+    for ent in entries {
+        if let Some(i) = get_root_idx(&ent.p) {
+            totals[i].0 += get_entry_size(ent, use_allocated);
+        }
+    }
+    // A modified directory's `*_below` change is already in the diffs (and moves) below it, only added and removed
+    // directories stand for their subtree
+    for (dt, d) in all_diffs {
+        if let Some(i) = get_root_idx(&d.p) {
+            if *dt == MOD_DT_IDX {
+                totals[i].1 += get_diff_size_here(d, use_allocated);
+            } else {
+                totals[i].1 += get_diff_size(d, use_allocated);
+            }
+        }
+    }
+    for (from, to) in move_to_paths {
+        let (Some(from_idx), Some(to_idx)) = (get_root_idx(from), get_root_idx(to)) else { continue };
+        if from_idx == to_idx {
+            continue;
+        }
+        let moved_size: i64 = entries.iter().filter(|ent| ent.p.starts_with(to)).map(|ent| get_entry_size(ent, use_allocated)).sum();
+        totals[from_idx].1 -= moved_size;
+        totals[to_idx].1 += moved_size;
+    }
+
+    let kind = if use_allocated {"allocated"} else {"apparent"};
+    for i in 0..roots.len() {
+        println!("Root {:?} size is: {} {}, change is: {}", roots[i], utility::get_shorthand_file_size(totals[i].0).trim_start_matches('+'), kind, utility::get_shorthand_file_size(totals[i].1));
+    }
+}
+
+// Size and change of each user and group, keyed by uid or gid. Bytes that aren't in a listed file (e.g. directories rolled
+// up by `--max-depth`, or the subtree of an added directory) belong to the directory's owner
+struct OwnerTotals {
+    users: HashMap<u32, (i64, i64)>,
+    groups: HashMap<u32, (i64, i64)>,
+    use_allocated: bool,
+}
+impl OwnerTotals {
+    // The sizes at the start of the report range, `entries` include the diffs before it
+    fn from_entries(entries: &Vec<CDirEntry>, use_allocated: bool) -> Self {
+        let mut ret = OwnerTotals { users: HashMap::new(), groups: HashMap::new(), use_allocated: use_allocated };
+        for ent in entries {
+            let mut listed: i64 = 0;
+            for f in ent.files.iter().chain(ent.symlinks.iter()) {
+                let size = get_file_size(f, use_allocated);
+                ret.add(&f.attrs, size);
+                listed += size;
+            }
+            ret.add(&ent.attrs, get_entry_size(ent, use_allocated) - listed);
+        }
+        return ret;
+    }
+
+    fn add(&mut self, attrs: &Attrs, size: i64) {
+        self.users.entry(attrs.uid).or_default().0 += size;
+        self.groups.entry(attrs.gid).or_default().0 += size;
+    }
+
+    fn add_change(&mut self, attrs: &Attrs, change: i64) {
+        self.add(attrs, change);
+        self.users.entry(attrs.uid).or_default().1 += change;
+        self.groups.entry(attrs.gid).or_default().1 += change;
+    }
+
+    // `entries` are the path sorted entries as of the end of the report range
+    fn add_diffs(&mut self, combined_diffs: &DiffEntry, entries: &Vec<CDirEntry>) {
+        for dt in 0..NUM_DT {
+            for d in &combined_diffs.diffs[dt] {
+                if ignore_dir_entry(d) {
+                    continue;
+                }
+                let mut listed: i64 = 0;
+                for f in d.files.iter().flatten().chain(d.symlinks.iter().flatten()) {
+                    if ignore_file_entry(f) {
+                        continue;
+                    }
+                    let change = get_file_diff_size(f, self.use_allocated);
+                    self.add_change(&f.attrs, change);
+                    listed += change;
+
+                    // A file that changed owner takes all of its bytes to the new owner, not just the change
+                    let Some(old) = f.old_attrs else { continue };
+                    let size_before = get_end_file_size(entries, &d.p, &f.bn, self.use_allocated) - change;
+                    self.add_change(&old, -size_before);
+                    self.add_change(&f.attrs, size_before);
+                }
+                // Modified directories list their own changes, added and removed ones also stand for their subtree
+                let mut change = get_diff_size_here(d, self.use_allocated);
+                if dt != MOD_DT_IDX {
+                    change = get_diff_size(d, self.use_allocated);
+                }
+                self.add_change(&d.attrs, change - listed);
+            }
+        }
+    }
+
+    fn print(&self) {
+        print_owner_lines("USR", &self.users, &utility::read_id_names("/etc/passwd"));
+        print_owner_lines("GRP", &self.groups, &utility::read_id_names("/etc/group"));
+    }
+}
+
+fn print_owner_lines(t: &str, totals: &HashMap<u32, (i64, i64)>, names: &HashMap<u32, String>) {
+    let mut sorted: Vec<(&u32, &(i64, i64))> = totals.iter().filter(|(_, v)| v.0 != 0 || v.1 != 0).collect();
+    sorted.sort_by(|a, b| b.1.0.cmp(&a.1.0).then(a.0.cmp(b.0)));
+    for (id, (size, change)) in sorted {
+        let name = names.get(id).cloned().unwrap_or(id.to_string());
+        println!("{}: {} {} ({})", t, name, utility::get_shorthand_file_size(*size).trim_start_matches('+'), utility::get_shorthand_file_size(*change));
+    }
+}
+
+// The size of a file as of the end of the report range, `entries` are path sorted
+fn get_end_file_size(entries: &Vec<CDirEntry>, p: &PathBuf, bn: &OsString, use_allocated: bool) -> i64 {
+    let Ok(idx) = entries.binary_search_by(|ent| ent.p.cmp(p)) else { return 0 };
+    let maybe_file = entries[idx].files.iter().chain(entries[idx].symlinks.iter()).find(|f| &f.bn == bn);
+    if maybe_file.is_none() {
+        return 0;
+    }
+    return get_file_size(maybe_file.unwrap(), use_allocated);
+}
+
+fn get_file_size(f: &FileEntry, use_allocated: bool) -> i64 {
+    if use_allocated {
+        return f.alloc_sz as i64;
+    }
+    return f.sz as i64;
+}
+
+fn get_file_diff_size(f: &FileEntryDiff, use_allocated: bool) -> i64 {
+    if use_allocated {
+        return f.alloc_sz as i64;
+    }
+    return f.sz as i64;
+}
+
+fn get_entry_size(ent: &CDirEntry, use_allocated: bool) -> i64 {
+    if use_allocated {
+        return ent.alloc_here;
+    }
+    return ent.size_here;
+}
+
+fn print_symlink_diffs(combined_diffs: &DiffEntry) {
+    for dt in 0..NUM_DT {
+        for d in &combined_diffs.diffs[dt] {
+            if ignore_dir_entry(d) {
+                continue;
+            }
+            // Symlinks in added or removed directories are changed along with the directory
+            for sdt in 0..NUM_DT {
+                for sl in &d.symlinks[sdt] {
+                    if ignore_file_entry(sl) {
+                        continue;
+                    }
+                    println!("LNK {}: {:?} -> {:?}", get_diff_type_shorthand(sdt), d.p.join(&sl.bn), sl.target.clone().unwrap_or_default());
+                }
+            }
+        }
+    }
+}
+
+// Ownership and permission changes have no size, so they're listed apart from the size changes
+fn print_attrs_diffs(combined_diffs: &DiffEntry) {
+    // Entries migrated from format 1 have no recorded attributes, so their first scan isn't a change
+    let unknown = Attrs::default();
+    for d in &combined_diffs.diffs[MOD_DT_IDX] {
+        if ignore_dir_entry(d) {
+            continue;
+        }
+        if let Some(old) = d.old_attrs.filter(|a| *a != unknown) {
+            print_attrs_change(&d.p, old, d.attrs);
+        }
+        for f in d.files[MOD_DT_IDX].iter().chain(d.symlinks[MOD_DT_IDX].iter()).chain(d.specials[MOD_DT_IDX].iter()) {
+            if let Some(old) = f.old_attrs.filter(|a| *a != unknown) {
+                print_attrs_change(&d.p.join(&f.bn), old, f.attrs);
+            }
+        }
+    }
+}
+
+fn print_attrs_change(p: &PathBuf, old: Attrs, new: Attrs) {
+    if old.mode != new.mode {
+        let mut note = "";
+        if new.mode & 0o002 != 0 && old.mode & 0o002 == 0 {
+            note = " (now world-writable)";
+        }
+        println!("PERM: {:?} {:04o} -> {:04o}{}", p, old.mode, new.mode, note);
+    }
+    if old.uid != new.uid || old.gid != new.gid {
+        println!("OWN: {:?} {}:{} -> {}:{}", p, old.uid, old.gid, new.uid, new.gid);
+    }
+}
+
+fn print_special_diffs(combined_diffs: &DiffEntry) {
+    for dt in 0..NUM_DT {
+        for d in &combined_diffs.diffs[dt] {
+            if ignore_dir_entry(d) {
+                continue;
+            }
+            // Special files have no size, so only ADD and REM are interesting
+            for sdt in [ADD_DT_IDX, REM_DT_IDX] {
+                for sp in &d.specials[sdt] {
+                    if ignore_file_entry(sp) {
+                        continue;
+                    }
+                    println!("SPC {}: {:?} ({})", get_diff_type_shorthand(sdt), d.p.join(&sp.bn), sp.kind);
+                }
+            }
+        }
+    }
+}
+
+fn get_diff_size(d: &CDirEntryDiff, use_allocated: bool) -> i64 {
+    if use_allocated {
+        return d.alloc_here + d.alloc_below;
+    }
+    return d.size_here + d.size_below;
+}
+
+fn get_diff_size_here(d: &CDirEntryDiff, use_allocated: bool) -> i64 {
+    if use_allocated {
+        return d.alloc_here;
+    }
+    return d.size_here;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::{merge_dir_diff_to_entry, TDiff};
+
+    const KIB: u64 = 1024;
+
+    fn get_attrs(uid: u32) -> Attrs {
+        return Attrs { uid: uid, gid: uid, mode: 0o644 };
+    }
+
+    fn get_file(bn: &str, sz: u64, uid: u32) -> FileEntry {
+        return FileEntry { bn: OsString::from(bn), sz: sz, alloc_sz: sz, attrs: get_attrs(uid), ..Default::default() };
+    }
+
+    // A MOD of directory `p` that only changes its files
+    fn get_mod_diff(p: &str, files: Vec<FileEntryDiff>) -> CDirEntryDiff {
+        let size: i64 = files.iter().map(|f| f.sz as i64).sum();
+        let mut diffs: [Vec<FileEntryDiff>; NUM_DT] = Default::default();
+        diffs[MOD_DT_IDX] = files;
+        return CDirEntryDiff {
+            p: PathBuf::from(p),
+            t_diff: TDiff { s_diff: 0, ns_diff: 0 },
+            files_here: 0,
+            files_below: 0,
+            dirs_here: 0,
+            dirs_below: 0,
+            size_here: size,
+            size_below: 0,
+            unique_size_here: size,
+            unique_size_below: 0,
+            alloc_here: size,
+            alloc_below: 0,
+            specials_here: 0,
+            specials_below: 0,
+            files: diffs,
+            symlinks: Default::default(),
+            specials: Default::default(),
+            attrs: get_attrs(0),
+            old_attrs: None,
+            not_traversed: false,
+            ct: None,
+        };
+    }
+
+    // The start of a report range after a scan where a user's file grew
+    #[test]
+    fn owner_totals_of_a_rebuilt_modified_file() {
+        let mut entries = vec![CDirEntry::new_for_test("/r", vec![get_file("big", 100 * KIB, 1000), get_file("other", 390 * KIB, 0)])];
+        let grown = FileEntryDiff { bn: OsString::from("big"), sz: 50 * KIB as i128, alloc_sz: 50 * KIB as i128, attrs: get_attrs(1000), ..Default::default() };
+        merge_dir_diff_to_entry(&mut entries[0], get_mod_diff("/r", vec![grown]));
+        assert_eq!(entries[0].files[0].sz, 150 * KIB);
+
+        let totals = OwnerTotals::from_entries(&entries, false);
+        assert_eq!(totals.users[&1000].0, 150 * KIB as i64);
+        assert_eq!(totals.users[&0].0, 390 * KIB as i64);
+        assert_eq!(totals.groups[&1000].0, 150 * KIB as i64);
+    }
+}
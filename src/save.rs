@@ -1,45 +1,918 @@
-use std::{cmp::Ordering, collections::{HashMap, HashSet}, ffi::OsString, fs::File, hash::{DefaultHasher, Hasher}, io::BufReader, os::unix::ffi::OsStrExt, path::PathBuf, time::{SystemTime, UNIX_EPOCH}, usize};
+use std::{cmp::Ordering, collections::{HashMap, HashSet}, ffi::OsString, fs::File, io::{BufReader, BufWriter, Read, Write}, os::unix::ffi::OsStrExt, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}, usize};
 use std::io;
-use crate::{diff::{get_entry_from_dir_diff, ignore_dir_entry, CDirEntryDiff, DiffEntry, DiffFile, FileEntryDiff, TDiff, ADD_DT_IDX, MOD_DT_IDX, NUM_DT, REM_DT_IDX}, scan::add_combined_diffs, walk::{CDirEntry, FileEntry}};
+use chksum_md5 as md5;
+use crate::{diff::{get_entry_from_dir_diff, ignore_dir_entry, CDirEntryDiff, DiffEntry, DiffFile, FileEntryDiff, TDiff, ADD_DT_IDX, MOD_DT_IDX, NUM_DT, REM_DT_IDX}, scan::{add_combined_diffs, ScanOptions}, walk::{Attrs, CDirEntry, FileEntry, FileKind}};
 
 const _START_VECTOR_BYTES: u64 = 8;
 
+// Store keys are the MD5 of a root's path as lowercase hex. Unlike `DefaultHasher` it's the same on every build, so a new
+// toolchain doesn't orphan existing stores. Roots are canonicalised before they're keyed
 pub fn get_hash_from_root_path(root: &std::path::PathBuf) -> String {
-    let root_hash_str: String;
+    let digest = md5::chksum(root.as_os_str().as_bytes().to_vec()).expect("failed to hash root path");
+    return digest.to_hex_lowercase();
+}
 
-    let mut hasher = DefaultHasher::new();
-    hasher.write(root.as_os_str().as_bytes());
-    root_hash_str = format!("{:x}", hasher.finish());
+// Profiles are keyed by their name, prefixed so a profile can't share a key with a root path
+pub fn get_hash_from_profile_name(name: &str) -> String {
+    return get_hash_from_root_path(&PathBuf::from(format!("profile:{}", name)));
+}
 
-    return root_hash_str;
+// Contents of a `_profile` file, the roots that are scanned and reported together under the profile's name
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ProfileFile {
+    pub name: String,
+    pub roots: Vec<PathBuf>,
 }
 
-pub fn read_save_file(file_path: PathBuf) -> io::Result<Vec<CDirEntry>> {
+pub fn read_profile_file(file_path: &PathBuf) -> io::Result<ProfileFile> {
     let fp = File::open(&file_path)?;
     let reader = BufReader::new(fp);
-    let res: Result<Vec<CDirEntry>, _> = bincode::deserialize_from(reader);
+    let res: Result<ProfileFile, _> = bincode::deserialize_from(reader);
 
     // Handle the deserialization error
     match res {
-        Ok(entries) => Ok(entries),
+        Ok(profile) => Ok(profile),
         Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Deserialization error: {}", e))),
     }
 }
 
+pub fn write_profile_file(file_path: &PathBuf, profile: &ProfileFile) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(file_path)?);
+    let res = bincode::serialize_into(&mut writer, profile);
+    if res.is_err() {
+        return Err(io::Error::new(io::ErrorKind::Other, format!("failed to write profile {:?}: {}", file_path, res.err().unwrap())));
+    }
+    return writer.flush();
+}
+
+// Every `_initial` and `_diffs` file starts with `SAVE_MAGIC`, then the format version as a little endian u32, then a
+// `SaveHeader`. The magic and version never change, so any version of the tool can tell what a file is
+const SAVE_MAGIC: &[u8; 8] = b"SEYE_RS\0";
+// Bump this (and add a step to `migrate_file`) whenever the layout of a header, `CDirEntry` or `DiffFile` changes.
+// Format 1 is the headerless layout from before the header existed, format 2 has no compression, formats 2 and 3 store
+// full paths
+pub const SAVE_FORMAT_VERSION: u32 = 4;
+// Older formats than this have to be migrated before they're read
+const OLDEST_READABLE_FORMAT_VERSION: u32 = 2;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum SaveKind {
+    Initial,
+    Diffs,
+}
+
+// How everything after the header is compressed
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    // The level is only a record of how it was written, any level decompresses the same way
+    Zstd(i32),
+}
+impl std::fmt::Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Compression::None => {
+                return write!(f, "uncompressed");
+            }
+            Compression::Zstd(level) => {
+                return write!(f, "zstd level {}", level);
+            }
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct SaveHeader {
+    pub kind: SaveKind,
+    // Version of seye_rs that wrote the file
+    pub tool_version: String,
+    // When the file was (last) written
+    pub created: SystemTime,
+    pub roots: Vec<PathBuf>,
+    pub options: ScanOptions,
+    pub compression: Compression,
+}
+impl SaveHeader {
+    pub fn new(kind: SaveKind, roots: &Vec<PathBuf>, options: &ScanOptions, compression: Compression) -> Self {
+        return SaveHeader {
+            kind: kind,
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            created: SystemTime::now(),
+            roots: roots.clone(),
+            options: options.clone(),
+            compression: compression,
+        };
+    }
+}
+
+// A format 2 header, its files are never compressed
+#[derive(serde::Deserialize)]
+struct SaveHeaderV2 {
+    kind: SaveKind,
+    tool_version: String,
+    created: SystemTime,
+    roots: Vec<PathBuf>,
+    options: ScanOptions,
+}
+
+fn write_header(writer: &mut impl Write, header: &SaveHeader) -> io::Result<()> {
+    writer.write_all(SAVE_MAGIC)?;
+    writer.write_all(&SAVE_FORMAT_VERSION.to_le_bytes())?;
+    let res = bincode::serialize_into(writer, header);
+    if res.is_err() {
+        return Err(io::Error::new(io::ErrorKind::Other, format!("failed to write header: {}", res.err().unwrap())));
+    }
+    return Ok(());
+}
+
+// Files without the magic are format 1
+fn read_format_version(reader: &mut impl Read) -> io::Result<u32> {
+    let mut magic = [0u8; 8];
+    let res = reader.read_exact(&mut magic);
+    if res.is_err() {
+        if res.as_ref().err().unwrap().kind() == io::ErrorKind::UnexpectedEof {
+            return Ok(1);
+        }
+        return Err(res.err().unwrap());
+    }
+    if &magic != SAVE_MAGIC {
+        return Ok(1);
+    }
+    let mut version = [0u8; 4];
+    reader.read_exact(&mut version)?;
+    return Ok(u32::from_le_bytes(version));
+}
+
+// Checks the file is a `kind` file of a format this version reads, so the entries after the header can be read. Returns
+// the header and the format version
+fn read_header(reader: &mut impl Read, file_path: &PathBuf, kind: SaveKind) -> io::Result<(SaveHeader, u32)> {
+    let version = read_format_version(reader)?;
+    if version < OLDEST_READABLE_FORMAT_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{:?} is in save format {}, this version of {} reads format {}, upgrade it with `{} migrate <output path>`", file_path, version, env!("CARGO_PKG_NAME"), SAVE_FORMAT_VERSION, env!("CARGO_PKG_NAME"))));
+    } else if version > SAVE_FORMAT_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{:?} is in save format {} from a newer version of seye_rs, this version only reads format {}", file_path, version, SAVE_FORMAT_VERSION)));
+    }
+    let res: Result<SaveHeader, _>;
+    if version == 2 {
+        res = bincode::deserialize_from(reader).map(|h: SaveHeaderV2| SaveHeader {
+            kind: h.kind,
+            tool_version: h.tool_version,
+            created: h.created,
+            roots: h.roots,
+            options: h.options,
+            compression: Compression::None,
+        });
+    } else {
+        res = bincode::deserialize_from(reader);
+    }
+    if res.is_err() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{:?} has a corrupt header: {}", file_path, res.err().unwrap())));
+    }
+    let header = res.unwrap();
+    if header.kind != kind {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{:?} is a {:?} file, expected a {:?} file", file_path, header.kind, kind)));
+    }
+    return Ok((header, version));
+}
+
+// Paths in `_initial` and `_diffs` files are front coded from format 4. An entry's path is replaced with the components
+// to add to the end of the path before it, and it's stored after the number of components to drop from that path first.
+// Entries are written in path order, so usually only a basename is stored
+#[derive(Default)]
+struct PathCoder {
+    prev: PathBuf,
+}
+impl PathCoder {
+    fn encode(&mut self, p: &Path) -> (u32, PathBuf) {
+        // Empty paths are ignored diffs, they're stored as is and don't change the previous path
+        if p.as_os_str().is_empty() {
+            return (0, PathBuf::new());
+        }
+        let prev_len = self.prev.components().count();
+        let mut common = self.prev.components().zip(p.components()).take_while(|(a, b)| a == b).count();
+        // A repeated path would be stored the same as an empty one, so its basename is stored again
+        if common == prev_len && common == p.components().count() {
+            common -= 1;
+        }
+        let rest: PathBuf = p.components().skip(common).collect();
+        self.prev = p.to_path_buf();
+        return ((prev_len - common) as u32, rest);
+    }
+
+    fn decode(&mut self, pop: u32, rest: &Path) -> PathBuf {
+        if pop == 0 && rest.as_os_str().is_empty() {
+            return PathBuf::new();
+        }
+        for _ in 0..pop {
+            self.prev.pop();
+        }
+        // An ancestor of the previous path only drops components, pushing nothing would add a trailing separator
+        if !rest.as_os_str().is_empty() {
+            self.prev.push(rest);
+        }
+        return self.prev.clone();
+    }
+}
+
+// Contents of an `_initial` file, the options in the header are kept so later scans can be checked against them
+pub struct SaveFile {
+    pub header: SaveHeader,
+    pub entries: Vec<CDirEntry>,
+}
+
+pub fn read_save_file(file_path: PathBuf) -> io::Result<SaveFile> {
+    let mut reader = open_save_file(&file_path)?;
+    let mut entries: Vec<CDirEntry> = Vec::with_capacity(reader.remaining as usize);
+    while reader.remaining > 0 {
+        reader.remaining -= 1;
+        let res = reader.read_entry();
+        if res.is_err() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Deserialization error: {}", res.err().unwrap())));
+        }
+        entries.push(res.unwrap());
+    }
+    return Ok(SaveFile {
+        header: reader.header,
+        entries: entries,
+    });
+}
+
+// Reads an `_initial` file's entries one at a time, for scans that don't fit in memory. After the header is the number of
+// entries, then the entries, each after the number of path components it drops
+pub struct SaveFileReader {
+    pub header: SaveHeader,
+    reader: Box<dyn Read + Send>,
+    remaining: u64,
+    // `None` for formats before 4, their entries have full paths
+    maybe_path_coder: Option<PathCoder>,
+}
+impl SaveFileReader {
+    fn read_entry(&mut self) -> bincode::Result<CDirEntry> {
+        match &mut self.maybe_path_coder {
+            Some(coder) => {
+                let (pop, mut ent): (u32, CDirEntry) = bincode::deserialize_from(&mut self.reader)?;
+                ent.p = coder.decode(pop, &ent.p);
+                return Ok(ent);
+            }
+            None => {
+                return bincode::deserialize_from(&mut self.reader);
+            }
+        }
+    }
+}
+impl Iterator for SaveFileReader {
+    type Item = io::Result<CDirEntry>;
+
+    fn next(&mut self) -> Option<io::Result<CDirEntry>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let res = self.read_entry();
+        if res.is_err() {
+            // Nothing after a bad entry can be read
+            self.remaining = 0;
+            return Some(Err(io::Error::new(io::ErrorKind::InvalidData, format!("Deserialization error: {}", res.err().unwrap()))));
+        }
+        return Some(Ok(res.unwrap()));
+    }
+}
+
+pub fn open_save_file(file_path: &PathBuf) -> io::Result<SaveFileReader> {
+    let mut file_reader = BufReader::new(File::open(file_path)?);
+    let (header, version) = read_header(&mut file_reader, file_path, SaveKind::Initial)?;
+    let mut reader = get_payload_reader(file_reader, header.compression)?;
+    let maybe_len: Result<u64, _> = bincode::deserialize_from(&mut reader);
+    if maybe_len.is_err() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Deserialization error: {}", maybe_len.err().unwrap())));
+    }
+    let mut maybe_path_coder = None;
+    if version >= 4 {
+        maybe_path_coder = Some(PathCoder::default());
+    }
+    return Ok(SaveFileReader {
+        header: header,
+        reader: reader,
+        remaining: maybe_len.unwrap(),
+        maybe_path_coder: maybe_path_coder,
+    });
+}
+
+// Writes the entries one at a time, so they don't all need to be in memory. Stops at the first entry that couldn't be read
+pub fn write_save_file(file_path: &PathBuf, header: &SaveHeader, num_entries: usize, entries: impl Iterator<Item = io::Result<CDirEntry>>) -> io::Result<()> {
+    let mut writer = PayloadWriter::new(BufWriter::new(File::create(file_path)?), header)?;
+    check_serialized(file_path, bincode::serialize_into(&mut writer, &(num_entries as u64)))?;
+    let mut coder = PathCoder::default();
+    for res in entries {
+        let mut ent = res?;
+        let pop;
+        (pop, ent.p) = coder.encode(&ent.p);
+        check_serialized(file_path, bincode::serialize_into(&mut writer, &(pop, &ent)))?;
+    }
+    return writer.finish();
+}
+
+// Writes fail on a full disk, which isn't a reason to panic
+fn check_serialized(file_path: &PathBuf, res: bincode::Result<()>) -> io::Result<()> {
+    if res.is_err() {
+        return Err(io::Error::new(io::ErrorKind::Other, format!("failed to write {:?}: {}", file_path, res.err().unwrap())));
+    }
+    return Ok(());
+}
+
+// `DiffFile` as it's stored from format 4, each directory diff's path is front coded like an `_initial` file's entries.
+// The paths of each diff type are coded as one sequence
+#[derive(serde::Serialize, serde::Deserialize)]
+struct StoredDiffFile {
+    has_merged_diff: bool,
+    timestamps: Vec<SystemTime>,
+    entries: Vec<StoredDiffEntry>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct StoredDiffEntry {
+    diffs: [Vec<(u32, CDirEntryDiff)>; NUM_DT],
+    move_to_paths: HashMap<PathBuf, PathBuf>,
+}
+
+fn get_stored_diff_file(diff_file: DiffFile) -> StoredDiffFile {
+    let entries = diff_file.entries.into_iter().map(|de| StoredDiffEntry {
+        diffs: de.diffs.map(|ds| {
+            let mut coder = PathCoder::default();
+            return ds.into_iter().map(|mut d| {
+                let pop;
+                (pop, d.p) = coder.encode(&d.p);
+                return (pop, d);
+            }).collect();
+        }),
+        move_to_paths: de.move_to_paths,
+    }).collect();
+    return StoredDiffFile {
+        has_merged_diff: diff_file.has_merged_diff,
+        timestamps: diff_file.timestamps,
+        entries: entries,
+    };
+}
+
+fn get_diff_file_from_stored(stored: StoredDiffFile) -> DiffFile {
+    let entries = stored.entries.into_iter().map(|sde| DiffEntry {
+        diffs: sde.diffs.map(|ds| {
+            let mut coder = PathCoder::default();
+            return ds.into_iter().map(|(pop, mut d)| {
+                d.p = coder.decode(pop, &d.p);
+                return d;
+            }).collect();
+        }),
+        move_to_paths: sde.move_to_paths,
+    }).collect();
+    return DiffFile {
+        has_merged_diff: stored.has_merged_diff,
+        timestamps: stored.timestamps,
+        entries: entries,
+    };
+}
+
 pub fn read_diff_file(file_path: &PathBuf) -> io::Result<DiffFile> {
+    return read_diff_file_and_header(file_path).map(|(diff_file, _)| diff_file);
+}
+
+fn read_diff_file_and_header(file_path: &PathBuf) -> io::Result<(DiffFile, SaveHeader)> {
     let fp = File::open(&file_path)?;
-    let reader = BufReader::new(fp);
-    let res: Result<DiffFile, _> = bincode::deserialize_from(reader);
+    let mut reader = BufReader::new(fp);
+    let (header, version) = read_header(&mut reader, file_path, SaveKind::Diffs)?;
+    let payload_reader = get_payload_reader(reader, header.compression)?;
+    let res: Result<DiffFile, _>;
+    if version >= 4 {
+        res = bincode::deserialize_from(payload_reader).map(get_diff_file_from_stored);
+    } else {
+        res = bincode::deserialize_from(payload_reader);
+    }
 
     // Handle the deserialization error
     match res {
-        Ok(entries) => Ok(entries),
+        Ok(diff_file) => Ok((diff_file, header)),
         Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Deserialization error: {}", e))),
     }
 }
 
-pub fn diff_saves(mut original_file: DiffFile, o: Vec<CDirEntry>, n: Vec<CDirEntry>, combined_diffs: DiffEntry, min_diff_bytes: usize, cache_merged_diffs: bool) -> DiffFile {
-    if o.len() == 0 && n.len() == 0 {
+pub fn write_diff_file(file_path: &PathBuf, header: &SaveHeader, diff_file: DiffFile) -> io::Result<()> {
+    let mut writer = PayloadWriter::new(BufWriter::new(File::create(file_path)?), header)?;
+    check_serialized(file_path, bincode::serialize_into(&mut writer, &get_stored_diff_file(diff_file)))?;
+    return writer.finish();
+}
+
+fn get_payload_reader(reader: BufReader<File>, compression: Compression) -> io::Result<Box<dyn Read + Send>> {
+    match compression {
+        Compression::None => {
+            return Ok(Box::new(reader));
+        }
+        Compression::Zstd(_) => {
+            return Ok(Box::new(zstd::stream::read::Decoder::with_buffer(reader)?));
+        }
+    }
+}
+
+// Writes the header, then compresses everything written after it as the header says. `finish` has to be called at the
+// end, it writes the end of the compressed stream
+enum PayloadWriter {
+    Plain(BufWriter<File>),
+    Zstd(zstd::stream::write::Encoder<'static, BufWriter<File>>),
+}
+impl PayloadWriter {
+    fn new(mut writer: BufWriter<File>, header: &SaveHeader) -> io::Result<Self> {
+        write_header(&mut writer, header)?;
+        match header.compression {
+            Compression::None => {
+                return Ok(PayloadWriter::Plain(writer));
+            }
+            Compression::Zstd(level) => {
+                return Ok(PayloadWriter::Zstd(zstd::stream::write::Encoder::new(writer, level)?));
+            }
+        }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self {
+            PayloadWriter::Plain(mut w) => {
+                return w.flush();
+            }
+            PayloadWriter::Zstd(e) => {
+                return e.finish()?.flush();
+            }
+        }
+    }
+}
+impl Write for PayloadWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            PayloadWriter::Plain(w) => w.write(buf),
+            PayloadWriter::Zstd(e) => e.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            PayloadWriter::Plain(w) => w.flush(),
+            PayloadWriter::Zstd(e) => e.flush(),
+        }
+    }
+}
+
+// Upgrades the `_initial` and `_diffs` files in `dir` to the current format and renames stores with keys from an older
+// version, in place. Returns the number of files changed
+pub fn migrate_store(dir: &PathBuf) -> io::Result<usize> {
+    let mut paths: Vec<PathBuf> = vec![];
+    for ent in std::fs::read_dir(dir)? {
+        let p = ent?.path();
+        let name = p.file_name().unwrap_or_default().to_string_lossy().to_string();
+        if p.is_file() && (name.ends_with("_initial") || name.ends_with("_diffs")) {
+            paths.push(p);
+        }
+    }
+    // `_diffs` files get their header from the (already upgraded) `_initial` file of the same key
+    paths.sort_by_key(|p| p.to_string_lossy().ends_with("_diffs"));
+
+    let mut num_changed = 0;
+    for p in &paths {
+        let version = read_format_version(&mut BufReader::new(File::open(p)?))?;
+        if version == SAVE_FORMAT_VERSION {
+            continue;
+        } else if version > SAVE_FORMAT_VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{:?} is in save format {} from a newer version of seye_rs, this version only reads format {}", p, version, SAVE_FORMAT_VERSION)));
+        }
+        migrate_file(p, version)?;
+        println!("migrated {:?} from save format {} to {}", p, version, SAVE_FORMAT_VERSION);
+        num_changed += 1;
+    }
+
+    for p in paths.iter().filter(|p| p.to_string_lossy().ends_with("_initial")) {
+        let key = p.file_name().unwrap_or_default().to_string_lossy().trim_end_matches("_initial").to_string();
+        let mut header = open_save_file(p)?.header;
+
+        // Older versions recorded the roots as typed, scans look them up by their canonical path
+        let mut roots = header.roots.clone();
+        for root in roots.iter_mut() {
+            let res = std::fs::canonicalize(&root);
+            if res.is_err() {
+                if root.is_relative() {
+                    eprintln!("failed to resolve {:?} ({}), run `migrate` from the directory it was scanned from", root, res.err().unwrap());
+                }
+                continue;
+            }
+            let canonical = res.unwrap();
+            if root.is_relative() {
+                println!("resolved {:?} to {:?}", root, canonical);
+            }
+            *root = canonical;
+        }
+        if roots != header.roots {
+            reroot_store(dir, &key, &header.roots, &roots)?;
+            header.roots = roots;
+            num_changed += 1;
+        }
+
+        let profile_path = dir.join(format!("{}_profile", key));
+        let mut maybe_profile = None;
+        let mut new_key = get_hash_from_root_path(&header.roots[0]);
+        if profile_path.exists() {
+            let name = read_profile_file(&profile_path)?.name;
+            new_key = get_hash_from_profile_name(&name);
+            maybe_profile = Some(name);
+        }
+        if new_key != key {
+            num_changed += rekey_store(dir, &key, &new_key)?;
+        }
+        add_to_index(dir, &new_key, maybe_profile, &header.roots)?;
+    }
+    return Ok(num_changed);
+}
+
+fn check_no_checkpoint(dir: &PathBuf, key: &str) -> io::Result<()> {
+    if dir.join(format!("{}_checkpoint", key)).exists() {
+        return Err(io::Error::new(io::ErrorKind::Other, format!("store {} has an interrupted scan, finish it with `scan --resume` (or delete its checkpoint) before migrating", key)));
+    }
+    return Ok(());
+}
+
+// Moves every path of store `key` from under `roots` to under `new_roots` (in the same order), in the `_initial`,
+// `_diffs` and `_profile` files. An interrupted scan has to be finished or discarded first
+fn reroot_store(dir: &PathBuf, key: &str, roots: &Vec<PathBuf>, new_roots: &Vec<PathBuf>) -> io::Result<()> {
+    check_no_checkpoint(dir, key)?;
+    let reroot = |p: &PathBuf| -> PathBuf {
+        return get_rerooted_path(p, roots, new_roots);
+    };
+
+    let initial_path = dir.join(format!("{}_initial", key));
+    let SaveFile { mut header, mut entries } = read_save_file(initial_path.clone())?;
+    header.roots = new_roots.clone();
+    for ent in entries.iter_mut() {
+        ent.p = reroot(&ent.p);
+    }
+    // The roots of a profile don't necessarily keep their order
+    entries.sort_by(|a, b| a.p.cmp(&b.p));
+    let tmp_path = dir.join(format!("{}_initial.migrating", key));
+    write_save_file(&tmp_path, &header, entries.len(), entries.into_iter().map(Ok))?;
+    std::fs::rename(&tmp_path, &initial_path)?;
+
+    let diffs_path = dir.join(format!("{}_diffs", key));
+    if diffs_path.exists() {
+        let (mut diff_file, mut header) = read_diff_file_and_header(&diffs_path)?;
+        header.roots = new_roots.clone();
+        for de in diff_file.entries.iter_mut() {
+            for dt in de.diffs.iter_mut() {
+                for d in dt.iter_mut() {
+                    d.p = reroot(&d.p);
+                }
+                dt.sort_by(|a, b| a.p.cmp(&b.p));
+            }
+            de.move_to_paths = de.move_to_paths.iter().map(|(from, to)| (reroot(from), reroot(to))).collect();
+        }
+        let tmp_path = dir.join(format!("{}_diffs.migrating", key));
+        write_diff_file(&tmp_path, &header, diff_file)?;
+        std::fs::rename(&tmp_path, &diffs_path)?;
+    }
+
+    let profile_path = dir.join(format!("{}_profile", key));
+    if profile_path.exists() {
+        let mut profile = read_profile_file(&profile_path)?;
+        profile.roots = profile.roots.iter().map(reroot).collect();
+        write_profile_file(&profile_path, &profile)?;
+    }
+    return Ok(());
+}
+
+// `p` under the deepest of `roots` it's in (roots of a profile can be nested), moved to the matching root of `new_roots`
+fn get_rerooted_path(p: &PathBuf, roots: &Vec<PathBuf>, new_roots: &Vec<PathBuf>) -> PathBuf {
+    let mut maybe_idx: Option<usize> = None;
+    for i in 0..roots.len() {
+        if !p.starts_with(&roots[i]) {
+            continue;
+        }
+        if maybe_idx.is_none() || roots[i].components().count() > roots[maybe_idx.unwrap()].components().count() {
+            maybe_idx = Some(i);
+        }
+    }
+    if maybe_idx.is_none() {
+        return p.clone();
+    }
+    let idx = maybe_idx.unwrap();
+    let rest = p.strip_prefix(&roots[idx]).unwrap();
+    if rest.as_os_str().is_empty() {
+        return new_roots[idx].clone();
+    }
+    return new_roots[idx].join(rest);
+}
+
+// Renames the files of store `key` to `new_key`, an interrupted scan has to be finished or discarded first
+fn rekey_store(dir: &PathBuf, key: &str, new_key: &str) -> io::Result<usize> {
+    check_no_checkpoint(dir, key)?;
+    let mut num_renamed = 0;
+    for suffix in ["_initial", "_diffs", "_profile"] {
+        let from = dir.join(format!("{}{}", key, suffix));
+        let to = dir.join(format!("{}{}", new_key, suffix));
+        if !from.exists() {
+            continue;
+        }
+        if to.exists() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("can't rename {:?} to {:?}, it already exists", from, to)));
+        }
+        std::fs::rename(&from, &to)?;
+        println!("renamed {:?} to {:?}", from, to);
+        num_renamed += 1;
+    }
+    return Ok(num_renamed);
+}
+
+// Contents of the `index` file in an output directory, which root path (or profile) each store key belongs to
+#[derive(serde::Serialize, serde::Deserialize, Default)]
+pub struct IndexFile {
+    pub stores: Vec<IndexEntry>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, PartialEq)]
+pub struct IndexEntry {
+    pub key: String,
+    pub maybe_profile: Option<String>,
+    pub roots: Vec<PathBuf>,
+}
+
+pub fn read_index_file(dir: &PathBuf) -> io::Result<IndexFile> {
+    let index_path = dir.join("index");
+    if !index_path.exists() {
+        return Ok(IndexFile::default());
+    }
+    let res: Result<IndexFile, _> = bincode::deserialize_from(BufReader::new(File::open(&index_path)?));
+    match res {
+        Ok(index) => Ok(index),
+        Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("failed to read index {:?}: {}", index_path, e))),
+    }
+}
+
+// Adds (or updates) store `key` in the index. A key that's already used by different roots is a hash collision
+pub fn add_to_index(dir: &PathBuf, key: &str, maybe_profile: Option<String>, roots: &Vec<PathBuf>) -> io::Result<()> {
+    let mut index = read_index_file(dir)?;
+    let new_entry = IndexEntry { key: key.to_string(), maybe_profile: maybe_profile, roots: roots.clone() };
+    match index.stores.iter().position(|e| e.key == key) {
+        Some(i) => {
+            if index.stores[i] == new_entry {
+                return Ok(());
+            }
+            if index.stores[i].maybe_profile.is_none() && new_entry.maybe_profile.is_none() {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("store key {} of {:?} is already used by {:?}", key, roots, index.stores[i].roots)));
+            }
+            index.stores[i] = new_entry;
+        }
+        None => {
+            index.stores.push(new_entry);
+        }
+    }
+
+    // Written next to the index and renamed over it, so it's never half written
+    let tmp_path = dir.join("index.tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    let res = bincode::serialize_into(&mut writer, &index);
+    if res.is_err() {
+        return Err(io::Error::new(io::ErrorKind::Other, format!("failed to write index: {}", res.err().unwrap())));
+    }
+    writer.flush()?;
+    return std::fs::rename(tmp_path, dir.join("index"));
+}
+
+// The upgraded file is written next to the old one and renamed over it, so an interrupted migration leaves the old file
+fn migrate_file(p: &PathBuf, version: u32) -> io::Result<()> {
+    let mut tmp_path = p.clone().into_os_string();
+    tmp_path.push(".migrating");
+    let tmp_path = PathBuf::from(tmp_path);
+    let is_initial = p.to_string_lossy().ends_with("_initial");
+    match version {
+        1 => {
+            // Format 1 files are the bincode of the entries (without a header) or the `DiffFile`, in the layout of `CDirEntryV1`
+            if is_initial {
+                let res: Result<Vec<CDirEntryV1>, _> = bincode::deserialize_from(BufReader::new(File::open(p)?));
+                if res.is_err() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("failed to read {:?} as save format 1: {}", p, res.err().unwrap())));
+                }
+                let entries: Vec<CDirEntry> = res.unwrap().into_iter().map(CDirEntryV1::into_current).collect();
+                let roots = get_roots_of_v1_store(p, &entries)?;
+                let header = SaveHeader::new(SaveKind::Initial, &roots, &ScanOptions::default(), Compression::None);
+                write_save_file(&tmp_path, &header, entries.len(), entries.into_iter().map(Ok))?;
+            } else {
+                let res: Result<DiffFileV1, _> = bincode::deserialize_from(BufReader::new(File::open(p)?));
+                if res.is_err() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("failed to read {:?} as save format 1: {}", p, res.err().unwrap())));
+                }
+                let initial_path = PathBuf::from(p.to_string_lossy().trim_end_matches("_diffs").to_string() + "_initial");
+                let initial_header = open_save_file(&initial_path)?.header;
+                let header = SaveHeader::new(SaveKind::Diffs, &initial_header.roots, &initial_header.options, Compression::None);
+                write_diff_file(&tmp_path, &header, res.unwrap().into_current())?;
+            }
+        }
+        2 | 3 => {
+            // Formats 2 and 3 are still read, their entries are written again with front coded paths
+            if is_initial {
+                let reader = open_save_file(p)?;
+                let mut header = reader.header.clone();
+                header.tool_version = env!("CARGO_PKG_VERSION").to_string();
+                let num_entries = reader.remaining as usize;
+                write_save_file(&tmp_path, &header, num_entries, reader)?;
+            } else {
+                let (diff_file, mut header) = read_diff_file_and_header(p)?;
+                header.tool_version = env!("CARGO_PKG_VERSION").to_string();
+                write_diff_file(&tmp_path, &header, diff_file)?;
+            }
+        }
+        _ => {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{:?} is in unknown save format {}", p, version)));
+        }
+    }
+    return std::fs::rename(tmp_path, p);
+}
+
+// Format 1 files don't have their roots. A profile's are in its `_profile` file, otherwise the root is the first entry
+fn get_roots_of_v1_store(p: &PathBuf, entries: &Vec<CDirEntry>) -> io::Result<Vec<PathBuf>> {
+    let key = p.file_name().unwrap_or_default().to_string_lossy().trim_end_matches("_initial").to_string();
+    let profile_path = p.with_file_name(format!("{}_profile", key));
+    if profile_path.exists() {
+        return Ok(read_profile_file(&profile_path)?.roots);
+    }
+    if entries.len() == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("failed to find the root path of {:?}", p)));
+    }
+    return Ok(vec![entries[0].p.clone()]);
+}
+
+// The layout of format 1, written by releases before the save header, kept as it was so those files can still be
+// migrated. Fields added since are filled with their defaults, except the unique sizes which are the apparent ones (each
+// link was counted)
+#[derive(serde::Deserialize)]
+struct FileEntryV1 {
+    sz: u64,
+    bn: OsString,
+    md: Option<SystemTime>,
+}
+impl FileEntryV1 {
+    fn into_current(self, kind: FileKind) -> FileEntry {
+        return FileEntry {
+            sz: self.sz,
+            bn: self.bn,
+            md: self.md,
+            kind: kind,
+            ..Default::default()
+        };
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct CDirEntryV1 {
+    files_here: usize,
+    files_below: usize,
+    dirs_here: usize,
+    dirs_below: usize,
+    size_here: i64,
+    size_below: i64,
+    p: PathBuf,
+    md: Option<SystemTime>,
+    md5: [u8; 16],
+    files: Vec<FileEntryV1>,
+    symlinks: Vec<FileEntryV1>,
+}
+impl CDirEntryV1 {
+    fn into_current(self) -> CDirEntry {
+        return CDirEntry {
+            files_here: self.files_here,
+            files_below: self.files_below,
+            dirs_here: self.dirs_here,
+            dirs_below: self.dirs_below,
+            size_here: self.size_here,
+            size_below: self.size_below,
+            unique_size_here: self.size_here,
+            unique_size_below: self.size_below,
+            alloc_here: 0,
+            alloc_below: 0,
+            specials_here: 0,
+            specials_below: 0,
+            p: self.p,
+            md: self.md,
+            ct: None,
+            md5: self.md5,
+            files: self.files.into_iter().map(|f| f.into_current(FileKind::File)).collect(),
+            symlinks: self.symlinks.into_iter().map(|f| f.into_current(FileKind::Symlink)).collect(),
+            specials: vec![],
+            attrs: Attrs::default(),
+            not_traversed: false,
+        };
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct TDiffV1 {
+    s_diff: i64,
+    ns_diff: i128,
+}
+impl TDiffV1 {
+    fn into_current(self) -> TDiff {
+        return TDiff { s_diff: self.s_diff, ns_diff: self.ns_diff };
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct FileEntryDiffV1 {
+    bn: OsString,
+    sz: i128,
+    t_diff: TDiffV1,
+}
+impl FileEntryDiffV1 {
+    fn into_current(self, kind: FileKind) -> FileEntryDiff {
+        return FileEntryDiff {
+            bn: self.bn,
+            sz: self.sz,
+            t_diff: self.t_diff.into_current(),
+            kind: kind,
+            ..Default::default()
+        };
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct CDirEntryDiffV1 {
+    p: PathBuf,
+    t_diff: TDiffV1,
+    files_here: usize,
+    files_below: usize,
+    dirs_here: usize,
+    dirs_below: usize,
+    size_here: i64,
+    size_below: i64,
+    files: [Vec<FileEntryDiffV1>; NUM_DT],
+    symlinks: [Vec<FileEntryDiffV1>; NUM_DT],
+}
+impl CDirEntryDiffV1 {
+    fn into_current(self) -> CDirEntryDiff {
+        return CDirEntryDiff {
+            p: self.p,
+            t_diff: self.t_diff.into_current(),
+            files_here: self.files_here,
+            files_below: self.files_below,
+            dirs_here: self.dirs_here,
+            dirs_below: self.dirs_below,
+            size_here: self.size_here,
+            size_below: self.size_below,
+            unique_size_here: self.size_here,
+            unique_size_below: self.size_below,
+            alloc_here: 0,
+            alloc_below: 0,
+            specials_here: 0,
+            specials_below: 0,
+            files: self.files.map(|fs| fs.into_iter().map(|f| f.into_current(FileKind::File)).collect()),
+            symlinks: self.symlinks.map(|fs| fs.into_iter().map(|f| f.into_current(FileKind::Symlink)).collect()),
+            specials: Default::default(),
+            attrs: Attrs::default(),
+            old_attrs: None,
+            not_traversed: false,
+            ct: None,
+        };
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct DiffEntryV1 {
+    diffs: [Vec<CDirEntryDiffV1>; NUM_DT],
+    move_to_paths: HashMap<PathBuf, PathBuf>,
+}
+
+#[derive(serde::Deserialize)]
+struct DiffFileV1 {
+    has_merged_diff: bool,
+    timestamps: Vec<SystemTime>,
+    entries: Vec<DiffEntryV1>,
+}
+impl DiffFileV1 {
+    fn into_current(self) -> DiffFile {
+        return DiffFile {
+            has_merged_diff: self.has_merged_diff,
+            timestamps: self.timestamps,
+            entries: self.entries.into_iter().map(|de| DiffEntry {
+                diffs: de.diffs.map(|ds| ds.into_iter().map(CDirEntryDiffV1::into_current).collect()),
+                move_to_paths: de.move_to_paths,
+            }).collect(),
+        };
+    }
+}
+
+// `o` and `n` are path sorted. `get_move_entries` returns the entries of `o` with the given paths, they're the only ones
+// needed to combine diffs with moves, so `o` doesn't have to be in memory
+pub fn diff_saves(
+    mut original_file: DiffFile, 
+    o: impl Iterator<Item = CDirEntry>, 
+    n: impl Iterator<Item = CDirEntry>, 
+    get_move_entries: impl Fn(&HashSet<PathBuf>) -> Vec<CDirEntry>, 
+    combined_diffs: DiffEntry, 
+    min_diff_bytes: usize, 
+    cache_merged_diffs: bool
+) -> DiffFile {
+    let mut o = o.peekable();
+    let mut n = n.peekable();
+    if o.peek().is_none() && n.peek().is_none() {
         return original_file;
     }
 
@@ -48,10 +921,6 @@ pub fn diff_saves(mut original_file: DiffFile, o: Vec<CDirEntry>, n: Vec<CDirEnt
         move_to_paths: HashMap::new(),
     };
 
-    let mut oi = 0;
-    let mut ni = 0;
-    let mut old = &o[0];
-    let mut new = &o[0];
     let mut remove_hash_idxs: HashMap<[u8; 16], usize> = HashMap::new();
     let mut add_hash_idxs: HashMap<[u8; 16], usize> = HashMap::new();
     
@@ -61,41 +930,39 @@ pub fn diff_saves(mut original_file: DiffFile, o: Vec<CDirEntry>, n: Vec<CDirEnt
     let mut moved_paths: Vec<PathBuf> = combined_diffs.move_to_paths.clone().into_values().collect();
     add_rem_set.extend(moved_paths.clone());
 
-    while oi < o.len() || ni < n.len() {
-        let old_left = oi < o.len();
-        if old_left {
-            old = &o[oi];
-        }
-        let new_left = ni < n.len();
-        if new_left {
-            new = &n[ni];
-        }
-
-        let mut diff_type: usize = ADD_DT_IDX; // -> new_left        
-        if old_left && new_left {
-            let old_new_cmp = old.p.cmp(&new.p);
-            match old_new_cmp {
-                Ordering::Equal => {
-                    diff_type = MOD_DT_IDX;
-                },
-                Ordering::Less => {
-                    // old item removed, NEXT old item *might* match CURRENT new
-                    diff_type = REM_DT_IDX;
-                    ni -= 1;
-                },
-                Ordering::Greater => {   
-                    // new item added, CURRENT old item *might* match NEXT new 
-                    diff_type = ADD_DT_IDX;
-                    oi -= 1;
+    loop {
+        let diff_type: usize;
+        match (o.peek(), n.peek()) {
+            (Some(old), Some(new)) => {
+                match old.p.cmp(&new.p) {
+                    Ordering::Equal => {
+                        diff_type = MOD_DT_IDX;
+                    },
+                    Ordering::Less => {
+                        // old item removed, NEXT old item *might* match CURRENT new
+                        diff_type = REM_DT_IDX;
+                    },
+                    Ordering::Greater => {   
+                        // new item added, CURRENT old item *might* match NEXT new 
+                        diff_type = ADD_DT_IDX;
+                    }
                 }
             }
-        } else if old_left {
-            diff_type = REM_DT_IDX;
+            (Some(_), None) => {
+                diff_type = REM_DT_IDX;
+            }
+            (None, Some(_)) => {
+                diff_type = ADD_DT_IDX;
+            }
+            (None, None) => {
+                break;
+            }
         }
 
         let diff_passes_threshold;
         match diff_type {
             ADD_DT_IDX => {
+                let new = &n.next().unwrap();
                 add_rem_set.insert(new.p.clone());
                 let maybe_move_match = remove_hash_idxs.get(&new.md5);
                 if maybe_move_match.is_some() {
@@ -108,15 +975,10 @@ pub fn diff_saves(mut original_file: DiffFile, o: Vec<CDirEntry>, n: Vec<CDirEnt
                         moved_paths.push(old_path.to_path_buf());
                     }
                     remove_hash_idxs.remove(&new.md5);
-
-                    oi += 1;
-                    ni += 1;
                     continue;
                 }
                 
                 if new.p.parent().is_some() && add_rem_set.contains(new.p.parent().unwrap()) {
-                    oi += 1;
-                    ni += 1;
                     continue;
                 }
                 add_hash_idxs.insert(new.md5, new_entry.diffs[ADD_DT_IDX].len());
@@ -130,12 +992,24 @@ pub fn diff_saves(mut original_file: DiffFile, o: Vec<CDirEntry>, n: Vec<CDirEnt
                     dirs_below: new.dirs_below,
                     size_here: new.size_here as i64,
                     size_below: new.size_below as i64,
+                    unique_size_here: new.unique_size_here,
+                    unique_size_below: new.unique_size_below,
+                    alloc_here: new.alloc_here,
+                    alloc_below: new.alloc_below,
+                    specials_here: new.specials_here,
+                    specials_below: new.specials_below,
                 
                     files: get_file_diffs(Vec::new(), new.files.to_vec(),),
                     symlinks: get_file_diffs(Vec::new(), new.symlinks.to_vec()),
+                    specials: get_file_diffs(Vec::new(), new.specials.to_vec()),
+                    attrs: new.attrs,
+                    old_attrs: None,
+                    not_traversed: new.not_traversed,
+                    ct: new.ct,
                 });
             },
             REM_DT_IDX => {
+                let old = &o.next().unwrap();
                 add_rem_set.insert(old.p.clone());
                 let maybe_move_match = add_hash_idxs.get(&old.md5);
                 if maybe_move_match.is_some() {
@@ -148,15 +1022,10 @@ pub fn diff_saves(mut original_file: DiffFile, o: Vec<CDirEntry>, n: Vec<CDirEnt
                         moved_paths.push(old.p.to_path_buf());
                     }
                     add_hash_idxs.remove(&old.md5);
-
-                    oi += 1;
-                    ni += 1;
                     continue;
                 }
 
                 if old.p.parent().is_some() && add_rem_set.contains(old.p.parent().unwrap()) {
-                    oi += 1;
-                    ni += 1;
                     continue;
                 }
                 remove_hash_idxs.insert(old.md5, new_entry.diffs[REM_DT_IDX].len());
@@ -170,16 +1039,28 @@ pub fn diff_saves(mut original_file: DiffFile, o: Vec<CDirEntry>, n: Vec<CDirEnt
                     dirs_below: old.dirs_below,
                     size_here: old.size_here as i64 * -1,
                     size_below: old.size_below as i64 * -1,
+                    unique_size_here: old.unique_size_here * -1,
+                    unique_size_below: old.unique_size_below * -1,
+                    alloc_here: old.alloc_here * -1,
+                    alloc_below: old.alloc_below * -1,
+                    specials_here: old.specials_here,
+                    specials_below: old.specials_below,
                 
                     files: get_file_diffs(old.files.to_vec(), Vec::new()),
                     symlinks: get_file_diffs(old.symlinks.to_vec(), Vec::new()),
+                    specials: get_file_diffs(old.specials.to_vec(), Vec::new()),
+                    attrs: old.attrs,
+                    old_attrs: None,
+                    not_traversed: old.not_traversed,
+                    ct: old.ct,
                 });
             },
             MOD_DT_IDX => {
-                let maybe_modified_dir_diff = get_maybe_modified_dir_diff(old.clone(), new.clone());
+                let maybe_modified_dir_diff = get_maybe_modified_dir_diff(o.next().unwrap(), n.next().unwrap());
                 match maybe_modified_dir_diff {
                     Some(d) => {
-                        diff_passes_threshold = d.size_here.abs() >= min_diff_bytes as i64;
+                        // Removing a hard link moves its size to the directory of another link, only the unique size changes there
+                        diff_passes_threshold = d.size_here.abs() >= min_diff_bytes as i64 || d.unique_size_here.abs() >= min_diff_bytes as i64 || d.alloc_here.abs() >= min_diff_bytes as i64 || has_unsized_diffs(&d);
                         if diff_passes_threshold {
                             new_entry.diffs[MOD_DT_IDX].push(d);
                         }
@@ -189,15 +1070,12 @@ pub fn diff_saves(mut original_file: DiffFile, o: Vec<CDirEntry>, n: Vec<CDirEnt
             },
             _ => { /* Should never be triggered here */ }
         }
-
-        oi += 1;
-        ni += 1;
     }
 
     // Apply filter given `-md` argument
     for i in 0..new_entry.diffs.len() {
         new_entry.diffs[i] = new_entry.diffs[i].clone().into_iter().filter(|it| {
-        return !ignore_dir_entry(it) && (it.size_here + it.size_below).abs() >= min_diff_bytes as i64}).collect();
+        return !ignore_dir_entry(it) && ((it.size_here + it.size_below).abs() >= min_diff_bytes as i64 || (it.unique_size_here + it.unique_size_below).abs() >= min_diff_bytes as i64 || (it.alloc_here + it.alloc_below).abs() >= min_diff_bytes as i64 || (i == MOD_DT_IDX && has_unsized_diffs(it)))}).collect();
     }
 
     // Pop off existing combined diff (if it exists)
@@ -226,9 +1104,10 @@ pub fn diff_saves(mut original_file: DiffFile, o: Vec<CDirEntry>, n: Vec<CDirEnt
     if cache_merged_diffs {
         let mut new_combined_diff = None;
         if combined_diff_entries.is_some() {
-            new_combined_diff = Some(add_diffs(&o, vec![combined_diff_entries.unwrap(), new_entry]));
+            let to_combine = vec![combined_diff_entries.unwrap(), new_entry];
+            new_combined_diff = Some(add_diffs(&get_move_entries(&get_move_from_paths(&to_combine)), to_combine));
         } else {
-            let maybe_combined = add_combined_diffs(&original_file, &o, None, None);
+            let maybe_combined = add_combined_diffs(&original_file, &get_move_entries(&get_move_from_paths(&original_file.entries)), None, None);
             // TODO: Handle error
             if maybe_combined.is_ok() {
                 new_combined_diff = Some(maybe_combined.unwrap());
@@ -247,8 +1126,13 @@ pub fn diff_saves(mut original_file: DiffFile, o: Vec<CDirEntry>, n: Vec<CDirEnt
     return original_file;
 }
 
+// `add_diffs` only looks up the scan entries of moved directories
+pub fn get_move_from_paths(diffs: &Vec<DiffEntry>) -> HashSet<PathBuf> {
+    return diffs.iter().flat_map(|d| d.move_to_paths.keys().cloned()).collect();
+}
+
 fn get_maybe_modified_dir_diff(ent_o: CDirEntry, ent_n: CDirEntry) -> Option<CDirEntryDiff> {    
-    let diff_here = ent_o.dirs_here != ent_n.dirs_here || ent_o.files_here != ent_n.files_here || ent_o.size_here != ent_n.size_here;
+    let diff_here = ent_o.dirs_here != ent_n.dirs_here || ent_o.files_here != ent_n.files_here || ent_o.size_here != ent_n.size_here || ent_o.unique_size_here != ent_n.unique_size_here || ent_o.alloc_here != ent_n.alloc_here || ent_o.not_traversed != ent_n.not_traversed || ent_o.symlinks != ent_n.symlinks || ent_o.specials != ent_n.specials || ent_o.attrs != ent_n.attrs || has_hash_changes(&ent_o.files, &ent_n.files) || has_attrs_changes(&ent_o.files, &ent_n.files) || has_nlink_changes(&ent_o.files, &ent_n.files);
     if !diff_here {
         return None;
     }
@@ -269,9 +1153,55 @@ fn get_maybe_modified_dir_diff(ent_o: CDirEntry, ent_n: CDirEntry) -> Option<CDi
         dirs_below: ent_n.dirs_below - ent_o.dirs_below,
         size_here: (ent_n.size_here - ent_o.size_here) as i64,
         size_below: (ent_n.size_below - ent_o.size_below) as i64,
+        unique_size_here: ent_n.unique_size_here - ent_o.unique_size_here,
+        unique_size_below: ent_n.unique_size_below - ent_o.unique_size_below,
+        alloc_here: ent_n.alloc_here - ent_o.alloc_here,
+        alloc_below: ent_n.alloc_below - ent_o.alloc_below,
+        // Counts are unsigned, a decrease wraps and is undone when the diff is added back
+        specials_here: ent_n.specials_here.wrapping_sub(ent_o.specials_here),
+        specials_below: ent_n.specials_below.wrapping_sub(ent_o.specials_below),
     
         files: get_file_diffs(ent_o.files.to_vec(), ent_n.files.to_vec()),
         symlinks: get_file_diffs(ent_o.symlinks.to_vec(), ent_n.symlinks.to_vec()),
+        specials: get_file_diffs(ent_o.specials.to_vec(), ent_n.specials.to_vec()),
+        attrs: ent_n.attrs,
+        old_attrs: if ent_o.attrs != ent_n.attrs {Some(ent_o.attrs)} else {None},
+        not_traversed: ent_n.not_traversed,
+        ct: ent_n.ct,
+    });
+}
+
+// Symlink, special file, content and ownership/permission changes can be tiny, so they're kept regardless of the `-md` threshold
+fn has_unsized_diffs(d: &CDirEntryDiff) -> bool {
+    return d.symlinks.iter().any(|ds| ds.len() > 0) || d.specials.iter().any(|ds| ds.len() > 0) || d.files[MOD_DT_IDX].iter().any(|f| f.hash_changed || f.old_attrs.is_some()) || d.old_attrs.is_some();
+}
+
+// `chmod` and `chown` don't change a file's mtime
+fn has_attrs_changes(o: &Vec<FileEntry>, n: &Vec<FileEntry>) -> bool {
+    let old_attrs: HashMap<&OsString, &Attrs> = o.iter().map(|f| (&f.bn, &f.attrs)).collect();
+    return n.iter().any(|f| {
+        let Some(old) = old_attrs.get(&f.bn) else { return false };
+        return **old != f.attrs;
+    });
+}
+
+// A file's link count changes without its mtime when another link to it is added or removed
+fn has_nlink_changes(o: &Vec<FileEntry>, n: &Vec<FileEntry>) -> bool {
+    let old_nlinks: HashMap<&OsString, u64> = o.iter().map(|f| (&f.bn, f.nlink)).collect();
+    return n.iter().any(|f| {
+        let Some(old) = old_nlinks.get(&f.bn) else { return false };
+        return *old != f.nlink;
+    });
+}
+
+fn has_hash_changes(o: &Vec<FileEntry>, n: &Vec<FileEntry>) -> bool {
+    if n.iter().all(|f| f.hash.is_none()) {
+        return false;
+    }
+    let old_hashes: HashMap<&OsString, &Option<[u8; 16]>> = o.iter().map(|f| (&f.bn, &f.hash)).collect();
+    return n.iter().any(|f| {
+        let Some(old_hash) = old_hashes.get(&f.bn) else { return false };
+        return old_hash.is_some() && f.hash.is_some() && **old_hash != f.hash;
     });
 }
 
@@ -325,6 +1255,17 @@ fn get_file_diffs(o: Vec<FileEntry>, n: Vec<FileEntry>) -> [Vec<FileEntryDiff>;
                 bn: ent_n.bn,
                 t_diff: get_t_diff_from_md(ent_n.md, false),
                 sz: ent_n.sz as i128,
+                alloc_sz: ent_n.alloc_sz as i128,
+                dev: ent_n.dev,
+                ino: ent_n.ino,
+                nlink: ent_n.nlink,
+                target: ent_n.target,
+                dangling: ent_n.dangling,
+                hash: ent_n.hash,
+                hash_changed: false,
+                kind: ent_n.kind,
+                attrs: ent_n.attrs,
+                old_attrs: None,
             });
             nidx += 1;
             continue;
@@ -338,6 +1279,17 @@ fn get_file_diffs(o: Vec<FileEntry>, n: Vec<FileEntry>) -> [Vec<FileEntryDiff>;
                 bn: ent_n.bn,
                 t_diff: get_t_diff_from_md(ent_n.md, false),
                 sz: ent_n.sz as i128,
+                alloc_sz: ent_n.alloc_sz as i128,
+                dev: ent_n.dev,
+                ino: ent_n.ino,
+                nlink: ent_n.nlink,
+                target: ent_n.target,
+                dangling: ent_n.dangling,
+                hash: ent_n.hash,
+                hash_changed: false,
+                kind: ent_n.kind,
+                attrs: ent_n.attrs,
+                old_attrs: None,
             });
 
             // TODO: This doesn't recognise if `base_path_o` was ALREADY added to diff, should check if it exists in diff and remove it...
@@ -368,6 +1320,17 @@ fn get_file_diffs(o: Vec<FileEntry>, n: Vec<FileEntry>) -> [Vec<FileEntryDiff>;
             bn: ent.bn.clone(),
             t_diff: get_t_diff_from_md(ent.md, true),
             sz: ent.sz as i128 * -1,
+            alloc_sz: ent.alloc_sz as i128 * -1,
+            dev: ent.dev,
+            ino: ent.ino,
+            nlink: ent.nlink,
+            target: ent.target.clone(),
+            dangling: ent.dangling,
+            hash: ent.hash,
+            hash_changed: false,
+            kind: ent.kind,
+            attrs: ent.attrs,
+            old_attrs: None,
         })
     }
 
@@ -419,9 +1382,20 @@ pub fn add_diffs(full_scan_entries: &Vec<CDirEntry>, diffs: Vec<DiffEntry>) -> D
                     dirs_below: old_a.dirs_below,
                     size_here: old_a.size_here as i64,
                     size_below: old_a.size_below as i64,
+                    unique_size_here: old_a.unique_size_here,
+                    unique_size_below: old_a.unique_size_below,
+                    alloc_here: old_a.alloc_here,
+                    alloc_below: old_a.alloc_below,
+                    specials_here: old_a.specials_here,
+                    specials_below: old_a.specials_below,
                 
                     files: get_file_diffs(Vec::new(), old_a.files.to_vec(),),
                     symlinks: get_file_diffs(Vec::new(), old_a.symlinks.to_vec()),
+                    specials: get_file_diffs(Vec::new(), old_a.specials.to_vec()),
+                    attrs: old_a.attrs,
+                    old_attrs: None,
+                    not_traversed: old_a.not_traversed,
+                    ct: old_a.ct,
                 };
                 ret.diffs[ADD_DT_IDX].push(add_b);
                 is_new_lookup[ADD_DT_IDX].push(true);
@@ -452,9 +1426,20 @@ pub fn add_diffs(full_scan_entries: &Vec<CDirEntry>, diffs: Vec<DiffEntry>) -> D
                     dirs_below: old_a.dirs_below,
                     size_here: old_a.size_here as i64 * -1,
                     size_below: old_a.size_below as i64 * -1,
+                    unique_size_here: old_a.unique_size_here * -1,
+                    unique_size_below: old_a.unique_size_below * -1,
+                    alloc_here: old_a.alloc_here * -1,
+                    alloc_below: old_a.alloc_below * -1,
+                    specials_here: old_a.specials_here,
+                    specials_below: old_a.specials_below,
                 
                     files: get_file_diffs(old_a.files.to_vec(), Vec::new()),
                     symlinks: get_file_diffs(old_a.symlinks.to_vec(), Vec::new()),
+                    specials: get_file_diffs(old_a.specials.to_vec(), Vec::new()),
+                    attrs: old_a.attrs,
+                    old_attrs: None,
+                    not_traversed: old_a.not_traversed,
+                    ct: old_a.ct,
                 };
                 is_new_lookup[REM_DT_IDX][dr_idx] = true;
             }
@@ -532,14 +1517,45 @@ fn merge_dir_diff(old: &CDirEntryDiff, new: &CDirEntryDiff) -> CDirEntryDiff {
         dirs_below: old.dirs_below + new.dirs_below,
         size_here: old.size_here + new.size_here,
         size_below: old.size_below + new.size_below,
+        unique_size_here: old.unique_size_here + new.unique_size_here,
+        unique_size_below: old.unique_size_below + new.unique_size_below,
+        alloc_here: old.alloc_here + new.alloc_here,
+        alloc_below: old.alloc_below + new.alloc_below,
+        specials_here: old.specials_here.wrapping_add(new.specials_here),
+        specials_below: old.specials_below.wrapping_add(new.specials_below),
     
         files: merge_file_types_diffs(&old.files, &new.files),
         symlinks: merge_file_types_diffs(&old.symlinks, &new.symlinks),
+        specials: merge_file_types_diffs(&old.specials, &new.specials),
+        attrs: new.attrs,
+        old_attrs: old.old_attrs.or(new.old_attrs),
+        not_traversed: new.not_traversed,
+        ct: new.ct,
     };
 }
 
 fn merge_file_types_diffs(old: &[Vec<FileEntryDiff>; NUM_DT], new: &[Vec<FileEntryDiff>; NUM_DT]) -> [Vec<FileEntryDiff>; NUM_DT] {
     let mut ret: [Vec<FileEntryDiff>; 3] = Default::default();
+    let mut old = old.clone();
+    let mut new = new.clone();
+
+    // A file added before and modified after is still just an ADD, otherwise the MOD
+    // is applied before the file exists and its changes are lost
+    new[MOD_DT_IDX].retain(|d| {
+        let maybe_add = old[ADD_DT_IDX].iter_mut().find(|a|{a.bn == d.bn});
+        if maybe_add.is_none() {
+            return true;
+        }
+        let add = maybe_add.unwrap();
+        let merged = merge_file_diff(add, d);
+        *add = FileEntryDiff {
+            hash_changed: false,
+            old_attrs: None,
+            ..merged
+        };
+        return false;
+    });
+
     let mut is_new_lookup: [Vec::<bool>; NUM_DT] = Default::default();
     for i in 0..NUM_DT {
         is_new_lookup[i] = vec![false; old[i].len()];
@@ -631,17 +1647,21 @@ fn merge_sorted_vec_duplicates<T: Clone>(add_rem_mod_arrays: &mut [Vec::<T>; 3],
     
     // if SUBSEQ_MATCH(a, b) -> MERGE else PUSH(a)
     let mut new_mod_items: Vec<T> = Vec::with_capacity(add_rem_mod_arrays[MOD_DT_IDX].len());
-    for i in 0..add_rem_mod_arrays[MOD_DT_IDX].len() {
+    let mut i = 0;
+    while i < add_rem_mod_arrays[MOD_DT_IDX].len() {
         let a = &add_rem_mod_arrays[MOD_DT_IDX][i];
         if (i + 1) < add_rem_mod_arrays[MOD_DT_IDX].len() {
             let b = &add_rem_mod_arrays[MOD_DT_IDX][i + 1];
             let is_next_equal = cmp(&a, &b) == Ordering::Equal;
             if is_next_equal {
+                // `b` is merged into `a`, so it's skipped too
                 new_mod_items.push(merge_elems(a, b));
+                i += 2;
                 continue;
             } 
         }
         new_mod_items.push(a.clone());
+        i += 1;
     }
     add_rem_mod_arrays[MOD_DT_IDX] = new_mod_items;
 }
@@ -651,15 +1671,32 @@ fn merge_file_diff(old: &FileEntryDiff, new: &FileEntryDiff) -> FileEntryDiff {
     return FileEntryDiff{
         bn: new.bn.clone(),
         sz: old.sz + new.sz,
+        alloc_sz: old.alloc_sz + new.alloc_sz,
         t_diff: TDiff{
             s_diff: old.t_diff.s_diff + new.t_diff.s_diff,
             ns_diff: old.t_diff.ns_diff + new.t_diff.ns_diff,
         },
+        dev: new.dev,
+        ino: new.ino,
+        nlink: new.nlink,
+        target: new.target.clone(),
+        dangling: new.dangling,
+        hash: new.hash,
+        hash_changed: old.hash_changed || new.hash_changed,
+        kind: new.kind,
+        attrs: new.attrs,
+        old_attrs: old.old_attrs.or(new.old_attrs),
     };
 }
 
 fn get_maybe_modified_file_diff(ent_o: FileEntry, ent_n: FileEntry) -> Option<FileEntryDiff> {    
-    if ent_o.md == ent_n.md {
+    // Symlinks can be retargeted or become dangling without their own mtime changing
+    let link_changed = ent_o.target != ent_n.target || ent_o.dangling != ent_n.dangling;
+    // Files rewritten in place can keep their size, and even their mtime
+    let hash_changed = ent_o.hash.is_some() && ent_n.hash.is_some() && ent_o.hash != ent_n.hash;
+    let attrs_changed = ent_o.attrs != ent_n.attrs;
+    let nlink_changed = ent_o.nlink != ent_n.nlink;
+    if ent_o.md == ent_n.md && !link_changed && !hash_changed && !attrs_changed && !nlink_changed {
         return None;
     }
 
@@ -669,9 +1706,148 @@ fn get_maybe_modified_file_diff(ent_o: FileEntry, ent_n: FileEntry) -> Option<Fi
     return Some(FileEntryDiff {
         bn: ent_n.bn,
         sz: ent_n.sz as i128 - ent_o.sz as i128,
+        alloc_sz: ent_n.alloc_sz as i128 - ent_o.alloc_sz as i128,
         t_diff: TDiff{
             s_diff: t_diff_n.s_diff - t_diff_o.s_diff,
             ns_diff: t_diff_n.ns_diff - t_diff_o.ns_diff,
         },
+        dev: ent_n.dev,
+        ino: ent_n.ino,
+        nlink: ent_n.nlink,
+        target: ent_n.target,
+        dangling: ent_n.dangling,
+        hash: ent_n.hash,
+        hash_changed: hash_changed,
+        kind: ent_n.kind,
+        attrs: ent_n.attrs,
+        old_attrs: if attrs_changed {Some(ent_o.attrs)} else {None},
     });
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A copy of a fixture store in a fresh temporary directory, so migrating it in place leaves the fixture alone
+    fn copy_fixture_store(name: &str) -> PathBuf {
+        let src = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name);
+        let dir = std::env::temp_dir().join(format!("seye_rs_test_{}_{}", name, std::process::id()));
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+        std::fs::create_dir_all(&dir).unwrap();
+        for ent in std::fs::read_dir(&src).unwrap() {
+            let p = ent.unwrap().path();
+            std::fs::copy(&p, dir.join(p.file_name().unwrap())).unwrap();
+        }
+        return dir;
+    }
+
+    // `baseline` was written by the release before the save header: a scan of a.txt (100B), link -> a.txt, sub/b.bin
+    // (2000B) and sub/deep/c (300B), then a second scan after adding sub/new (5000B) and removing sub/deep/c
+    #[test]
+    fn migrate_store_reads_baseline_format() {
+        let dir = copy_fixture_store("baseline");
+        let root = PathBuf::from("/tmp/seye-fixture/root");
+        migrate_store(&dir).unwrap();
+
+        let key = get_hash_from_root_path(&root);
+        let save = read_save_file(dir.join(format!("{}_initial", key))).unwrap();
+        assert_eq!(save.header.kind, SaveKind::Initial);
+        assert_eq!(save.header.roots, vec![root.clone()]);
+        let paths: Vec<PathBuf> = save.entries.iter().map(|e| e.p.clone()).collect();
+        assert_eq!(paths, vec![root.clone(), root.join("sub"), root.join("sub/deep")]);
+        assert_eq!(save.entries[0].size_here, 200);
+        assert_eq!(save.entries[0].size_below, 2300);
+        assert_eq!(save.entries[0].unique_size_here, 200);
+        let mut files: Vec<&OsString> = save.entries[0].files.iter().map(|f| &f.bn).collect();
+        files.sort();
+        assert_eq!(files, vec!["a.txt", "link"]);
+        assert_eq!(save.entries[1].files[0].sz, 2000);
+
+        let diff_file = read_diff_file(&dir.join(format!("{}_diffs", key))).unwrap();
+        assert_eq!(diff_file.entries.len(), 1);
+        assert_eq!(diff_file.timestamps.len(), 1);
+        let mods = &diff_file.entries[0].diffs[MOD_DT_IDX];
+        let sub = mods.iter().find(|d| d.p == root.join("sub")).unwrap();
+        assert_eq!(sub.size_here, 5000);
+        assert_eq!(sub.size_below, -300);
+        assert_eq!(sub.files[ADD_DT_IDX].len(), 1);
+        assert_eq!(sub.files[ADD_DT_IDX][0].bn, "new");
+        assert_eq!(sub.files[ADD_DT_IDX][0].sz, 5000);
+        let deep = mods.iter().find(|d| d.p == root.join("sub/deep")).unwrap();
+        assert_eq!(deep.files[REM_DT_IDX].len(), 1);
+        assert_eq!(deep.files[REM_DT_IDX][0].bn, "c");
+
+        // Already current, so a second run changes nothing
+        assert_eq!(migrate_store(&dir).unwrap(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Encodes `paths` in order, then decodes them and checks they come back byte for byte
+    fn assert_path_coder_round_trip(paths: &[&str]) -> Vec<(u32, PathBuf)> {
+        let mut encoder = PathCoder::default();
+        let encoded: Vec<(u32, PathBuf)> = paths.iter().map(|p| encoder.encode(Path::new(p))).collect();
+        let mut decoder = PathCoder::default();
+        for i in 0..paths.len() {
+            let decoded = decoder.decode(encoded[i].0, &encoded[i].1);
+            assert_eq!(decoded.as_os_str(), paths[i], "path {} of {:?} (encoded as {:?})", i, paths, encoded);
+        }
+        return encoded;
+    }
+
+    #[test]
+    fn path_coder_round_trips() {
+        let encoded = assert_path_coder_round_trip(&["/", "/a", "/a/b", "/a/b/c", "/a/d", "/e"]);
+        assert_eq!(encoded[0], (0, PathBuf::from("/")));
+        assert_eq!(encoded[3], (0, PathBuf::from("c")));
+        assert_eq!(encoded[4], (2, PathBuf::from("d")));
+        assert_eq!(encoded[5], (2, PathBuf::from("e")));
+
+        // Root paths and repeated paths
+        assert_path_coder_round_trip(&["/", "/"]);
+        let encoded = assert_path_coder_round_trip(&["/a/b", "/a/b", "/a/b/c", "/a/b/c"]);
+        assert_eq!(encoded[1], (1, PathBuf::from("b")));
+
+        // Empty paths (ignored diffs) don't change the previous path
+        let encoded = assert_path_coder_round_trip(&["/a/b", "", "/a/b/c", "", "", "/a/d"]);
+        assert_eq!(encoded[1], (0, PathBuf::new()));
+        assert_eq!(encoded[2], (0, PathBuf::from("c")));
+        assert_eq!(encoded[5], (2, PathBuf::from("d")));
+
+        // Going back up several levels, to another root, to an ancestor and to the root
+        assert_path_coder_round_trip(&["/a/b/c/d/e", "/a/f", "/x/y/z", "/x", "/x/y/z", "/"]);
+        assert_path_coder_round_trip(&["data/sub/deep", "data", "other/sub"]);
+    }
+
+    #[test]
+    fn get_rerooted_path_moves_paths_under_the_deepest_root() {
+        let roots = vec![PathBuf::from("data"), PathBuf::from("data/nested"), PathBuf::from("./other")];
+        let new_roots = vec![PathBuf::from("/srv/data"), PathBuf::from("/mnt/nested"), PathBuf::from("/srv/other")];
+        let reroot = |p: &str| get_rerooted_path(&PathBuf::from(p), &roots, &new_roots);
+        assert_eq!(reroot("data"), PathBuf::from("/srv/data"));
+        assert_eq!(reroot("data/sub/deep"), PathBuf::from("/srv/data/sub/deep"));
+        assert_eq!(reroot("data/nested/x"), PathBuf::from("/mnt/nested/x"));
+        assert_eq!(reroot("./other/y"), PathBuf::from("/srv/other/y"));
+        // Only whole components match
+        assert_eq!(reroot("database/z"), PathBuf::from("database/z"));
+    }
+
+    // A file that's added and later has its mode changed
+    #[test]
+    fn merge_file_types_diffs_folds_mod_into_earlier_add() {
+        let old_attrs = Attrs { uid: 1000, gid: 1000, mode: 0o644 };
+        let new_attrs = Attrs { uid: 1000, gid: 1000, mode: 0o600 };
+        let mut old: [Vec<FileEntryDiff>; NUM_DT] = Default::default();
+        old[ADD_DT_IDX].push(FileEntryDiff { bn: OsString::from("f"), sz: 100, attrs: old_attrs, ..Default::default() });
+        let mut new: [Vec<FileEntryDiff>; NUM_DT] = Default::default();
+        new[MOD_DT_IDX].push(FileEntryDiff { bn: OsString::from("f"), sz: 20, attrs: new_attrs, old_attrs: Some(old_attrs), ..Default::default() });
+
+        let merged = merge_file_types_diffs(&old, &new);
+        assert_eq!(merged[ADD_DT_IDX].len(), 1);
+        assert_eq!(merged[MOD_DT_IDX].len(), 0);
+        assert_eq!(merged[ADD_DT_IDX][0].sz, 120);
+        assert_eq!(merged[ADD_DT_IDX][0].attrs, new_attrs);
+        assert_eq!(merged[ADD_DT_IDX][0].old_attrs, None);
+    }
+}
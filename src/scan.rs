@@ -1,76 +1,440 @@
-use std::{collections::HashMap, fs::{exists, File}, io::{BufWriter, Error}, time::SystemTime};
+use std::{cell::RefCell, collections::{HashMap, HashSet}, fs::exists, io::Error, sync::Mutex, time::SystemTime};
 use rayon::{slice::ParallelSliceMut};
 
-use crate::{diff::{add_diffs_to_items, get_entry_from_dir_diff, ignore_dir_entry, merge_dir_diff_to_entry, CDirEntryDiff, DiffEntry, DiffFile}, save::{add_diffs, get_hash_from_root_path}, utility::collect_from_root};
-use crate::{save::{diff_saves, read_diff_file, read_save_file}, walk::CDirEntry};
+use crate::{progress::{self, Progress, ProgressMode}, throttle::RateLimiter, diff::{add_diffs_to_items, get_entry_from_dir_diff, ignore_dir_entry, merge_dir_diff_to_entry, CDirEntryDiff, DiffEntry, DiffFile, ADD_DT_IDX, MOD_DT_IDX, REM_DT_IDX}, save::{add_diffs, add_to_index, get_hash_from_profile_name, get_hash_from_root_path, get_move_from_paths, read_profile_file, write_profile_file, ProfileFile, open_save_file, write_save_file}, utility::collect_pending};
+use crate::checkpoint::{self, Checkpoint, WalkState};
+use crate::spill::{get_entry_mem_size, remove_runs, ExternalSorter, MergeIter, SortedRuns, UntilErr};
+use crate::{save::{diff_saves, read_diff_file, read_save_file, write_diff_file, Compression, SaveHeader, SaveKind}, walk::{CDirEntry, HashMode, PrevScan, WalkError, WalkOptions, WalkRoot}};
+use serde::{Deserialize, Serialize};
 
-pub fn scan(target_path: std::path::PathBuf, output_path: std::path::PathBuf, min_diff_bytes: usize, num_threads: usize, thread_add_dir_limit: usize, cache_merged_diffs: bool) -> Result<(usize, usize), Error> {
-    let root_path_hash = get_hash_from_root_path(&target_path);
+// Pseudo and virtual filesystems that aren't walked by default, their files don't take up disk space and some of them
+// block when read
+pub const DEFAULT_SKIP_FS_TYPES: [&str; 18] = [
+    "proc", "sysfs", "devtmpfs", "devpts", "cgroup", "cgroup2", "securityfs", "debugfs", "tracefs", "pstore", "bpf",
+    "configfs", "fusectl", "mqueue", "hugetlbfs", "binfmt_misc", "efivarfs", "nsfs",
+];
+
+// Options that change what a scan collects, these are stored with the `_initial` save and must match on later scans
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ScanOptions {
+    pub exclude: Vec<String>,
+    pub include: Vec<String>,
+    pub one_file_system: bool,
+    pub hash: HashMode,
+    // Directories deeper than this don't get entries, their totals are rolled into their ancestor at this depth
+    pub max_depth: Option<usize>,
+    // Mounts of these filesystem types are recorded as 'not traversed', sorted
+    pub skip_fs_types: Vec<String>,
+}
+impl Default for ScanOptions {
+    fn default() -> Self {
+        return ScanOptions {
+            exclude: vec![],
+            include: vec![],
+            one_file_system: false,
+            hash: HashMode::default(),
+            max_depth: None,
+            skip_fs_types: get_sorted_fs_types(DEFAULT_SKIP_FS_TYPES.iter().map(|t| t.to_string()).collect()),
+        };
+    }
+}
+impl ScanOptions {
+    // `list` is comma separated, plain types replace the default list and types prefixed with '+' or '-' are added to or
+    // removed from it. 'none' walks every filesystem
+    pub fn set_skip_fs_types(&mut self, list: &str) -> std::io::Result<()> {
+        let mut types: Vec<String> = DEFAULT_SKIP_FS_TYPES.iter().map(|t| t.to_string()).collect();
+        let items: Vec<&str> = list.split(',').map(|t| t.trim()).collect();
+        if items.iter().any(|t| !t.starts_with('+') && !t.starts_with('-')) {
+            types.clear();
+        }
+        for t in items {
+            if t.len() == 0 || t == "+" || t == "-" {
+                return Err(Error::new(std::io::ErrorKind::InvalidInput, format!("invalid filesystem type list '{}', types can't be empty", list)));
+            }
+            if t == "none" {
+                continue;
+            }
+            if let Some(removed) = t.strip_prefix('-') {
+                types.retain(|el| el != removed);
+                continue;
+            }
+            types.push(t.trim_start_matches('+').to_string());
+        }
+        self.skip_fs_types = get_sorted_fs_types(types);
+        return Ok(());
+    }
+}
+
+fn get_sorted_fs_types(mut types: Vec<String>) -> Vec<String> {
+    types.sort();
+    types.dedup();
+    return types;
+}
+
+// Options that only change how a scan runs, they can differ between scans of the same store
+#[derive(Debug, Clone, Copy)]
+pub struct ScanFlags {
+    pub min_diff_bytes: usize,
+    pub thread_add_dir_limit: usize,
+    pub cache_merged_diffs: bool,
+    pub incremental: bool,
+    pub progress_mode: ProgressMode,
+    pub resume: bool,
+    pub maybe_memory_limit: Option<usize>,
+    pub maybe_iops_limit: Option<u64>,
+    pub compression: Compression,
+}
+
+// What a scan walks and the key its files are stored under in the output directory, either a single root keyed by its
+// path or the member roots of a named profile
+pub struct ScanTarget {
+    pub key: String,
+    pub roots: Vec<std::path::PathBuf>,
+    pub maybe_profile: Option<String>,
+}
+impl ScanTarget {
+    pub fn new(root: std::path::PathBuf) -> Self {
+        return ScanTarget {
+            key: get_hash_from_root_path(&root),
+            roots: vec![root],
+            maybe_profile: None,
+        };
+    }
+
+    // Uses the roots stored for profile `name`. The first scan of a profile stores `roots`, after that they can be left
+    // out but must match if given
+    pub fn from_profile(output_path: &std::path::PathBuf, name: &str, roots: Vec<std::path::PathBuf>) -> std::io::Result<Self> {
+        let key = get_hash_from_profile_name(name);
+        let path_to_profile = output_path.join(format!("{}_profile", key));
+        if exists(&path_to_profile)? {
+            let profile = read_profile_file(&path_to_profile)?;
+            if roots.len() > 0 && roots != profile.roots {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("profile '{}' has roots {:?}, leave them out or use a new profile name", name, profile.roots)));
+            }
+            return Ok(ScanTarget { key: key, roots: profile.roots, maybe_profile: Some(name.to_string()) });
+        }
+        if roots.len() == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("profile '{}' doesn't exist, give its roots on its first scan", name)));
+        }
+
+        // Entries are only unique by path if no root is inside another
+        for (i, r) in roots.iter().enumerate() {
+            if roots.iter().enumerate().any(|(j, other)| i != j && r.starts_with(other)) {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("profile root {:?} overlaps another root", r)));
+            }
+        }
+        write_profile_file(&path_to_profile, &ProfileFile { name: name.to_string(), roots: roots.clone() })?;
+        return Ok(ScanTarget { key: key, roots: roots, maybe_profile: Some(name.to_string()) });
+    }
+}
+
+pub fn scan(target: &ScanTarget, output_path: std::path::PathBuf, scan_options: ScanOptions, flags: ScanFlags) -> Result<(usize, usize, Vec<WalkError>), Error> {
+    let root_path_hash = &target.key;
     let mut path_to_initial = output_path.clone();
     path_to_initial.push(format!("{}_initial", root_path_hash));
     let mut path_to_diff = output_path.clone();
     path_to_diff.push(format!("{}_diffs", root_path_hash));
-    
-    let maybe_curr_scan = collect_from_root(target_path, num_threads, thread_add_dir_limit);
-    if maybe_curr_scan.is_err() {
-        return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to do MT walk: {:?}", maybe_curr_scan.err())))
+    if flags.maybe_memory_limit.is_some() && flags.incremental {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "--incremental needs the previous scan in memory, it can't be used with --memory-limit"));
+    }
+
+    // Check the options match the initial scan BEFORE walking, a mismatch would show up as mass ADD/REM diffs
+    let initial_scan_exists = exists(&path_to_initial)?;
+    let mut initial_scan: Vec<CDirEntry> = vec![];
+    if initial_scan_exists {
+        let maybe_last_header = open_save_file(&path_to_initial).map(|r| r.header);
+        if maybe_last_header.is_err() {
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to read entries from file: {:?}", maybe_last_header.err())))
+        }
+        let last_header = maybe_last_header.unwrap();
+        if last_header.roots != target.roots {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("the saved scan is of {:?}, not {:?}", last_header.roots, target.roots)));
+        }
+        let last_options = last_header.options;
+        if last_options != scan_options {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("scan options {:?} don't match the options of the initial scan {:?}, use the same options or a different output directory", scan_options, last_options)));
+        }
+        if flags.maybe_memory_limit.is_none() {
+            initial_scan = read_save_file(path_to_initial.clone())?.entries;
+        }
+    }
+    // Only once the scan is known to be of this store, a rejected scan doesn't change the index
+    add_to_index(&output_path, &target.key, target.maybe_profile.clone(), &target.roots)?;
+
+    // Rebuild the previous state: initial scan + all diffs
+    let mut diff_file: DiffFile = DiffFile { has_merged_diff: true, timestamps: vec![], entries: vec![] };
+    let mut combined_diffs: DiffEntry = DiffEntry { diffs: Default::default(), move_to_paths: HashMap::new() };
+    let mut maybe_spilled_prev: Option<SortedRuns<CDirEntry>> = None;
+    if initial_scan_exists {
+        let diff_exists = exists(&path_to_diff)?;
+        if diff_exists {
+            diff_file = read_diff_file(&path_to_diff)?;
+            
+            // Without the initial scan in memory, only the moved directories are read for `add_diffs`
+            let mut move_entries = vec![];
+            if flags.maybe_memory_limit.is_some() {
+                let from_paths = get_move_from_paths(&diff_file.entries);
+                for res in open_save_file(&path_to_initial)? {
+                    let ent = res?;
+                    if from_paths.contains(&ent.p) {
+                        move_entries.push(ent);
+                    }
+                }
+            }
+            let res: Result<DiffEntry, Error> = add_combined_diffs(&diff_file, if flags.maybe_memory_limit.is_some() {&move_entries} else {&initial_scan}, None, None);
+            if res.is_err() {
+                return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("failed to add combined diffs to scan: {:?}", res.err())))
+            }
+            combined_diffs = res.unwrap();
+        }
+
+        match flags.maybe_memory_limit {
+            Some(limit) => {
+                let mut prev_sorter = ExternalSorter::new(&output_path, &format!("{}_prev_run_", root_path_hash), limit, get_entry_mem_size, |a, b| a.p.cmp(&b.p));
+                remove_runs(&output_path, &format!("{}_prev_run_", root_path_hash), &vec![])?;
+                remove_runs(&output_path, &format!("{}_curr_run_", root_path_hash), &vec![])?;
+                let res = open_save_file(&path_to_initial).and_then(|initial| apply_combined_diffs_spilled(initial, &combined_diffs, &mut prev_sorter)).and_then(|_| prev_sorter.finish(true));
+                if res.is_err() {
+                    _ = remove_runs(&output_path, &format!("{}_prev_run_", root_path_hash), &vec![]);
+                    return Err(res.err().unwrap());
+                }
+                maybe_spilled_prev = Some(res.unwrap());
+            }
+            None => {
+                apply_combined_diffs(&mut initial_scan, &mut combined_diffs)?;
+            }
+        }
+    }
+
+    let mut walk_options = WalkOptions::new(&scan_options)?;
+    for r in &target.roots {
+        walk_options.roots.push(WalkRoot::new(r, scan_options.one_file_system)?);
+    }
+    if flags.incremental && initial_scan_exists {
+        walk_options.prev_scan = Some(PrevScan::new(&initial_scan));
+    }
+
+    // The walk is checkpointed as it goes, so an interrupted or crashed scan can continue with `--resume`
+    checkpoint::handle_interrupts();
+    let checkpoint = Checkpoint::new(&output_path, root_path_hash);
+    let checkpoint_exists = checkpoint.exists()?;
+    let mut walk_state = WalkState::new(&target.roots);
+    if flags.resume && checkpoint_exists {
+        walk_state = checkpoint.load(&target.roots, &scan_options, flags.maybe_memory_limit)?;
+        println!("resuming scan from checkpoint: {} directories walked, {} pending", walk_state.entries.len() + walk_state.num_spilled(), walk_state.pending.len());
+    } else {
+        if flags.resume {
+            println!("no checkpoint to resume from, starting a new scan");
+        } else if checkpoint_exists {
+            eprintln!("discarding the checkpoint of an interrupted scan, use --resume to continue it instead");
+        }
+        checkpoint.create(&target.roots, &scan_options)?;
+        if let Some(limit) = flags.maybe_memory_limit {
+            walk_state.set_spill(checkpoint.new_spill(limit));
+        }
+    }
+
+    let progress = Progress::default();
+    walk_options.progress = Some(&progress);
+    let rate_limiter = flags.maybe_iops_limit.map(RateLimiter::new);
+    walk_options.rate_limit = rate_limiter.as_ref();
+    let walk_state = Mutex::new(walk_state);
+    let checkpoint_res = std::thread::scope(|s| {
+        let (stop_progress_tx, stop_progress_rx) = std::sync::mpsc::channel();
+        let (stop_checkpoint_tx, stop_checkpoint_rx) = std::sync::mpsc::channel();
+        s.spawn(|| progress::report_until_stopped(&progress, flags.progress_mode, stop_progress_rx));
+        let saver = s.spawn(|| checkpoint.save_until_stopped(&walk_state, stop_checkpoint_rx));
+        collect_pending(&walk_state, flags.thread_add_dir_limit, &walk_options);
+        drop(stop_progress_tx);
+        drop(stop_checkpoint_tx);
+        return saver.join().unwrap();
+    });
+    if checkpoint::is_interrupted() {
+        if checkpoint_res.is_err() {
+            return Err(std::io::Error::new(std::io::ErrorKind::Interrupted, format!("scan interrupted and failed to save checkpoint: {}", checkpoint_res.err().unwrap())));
+        }
+        return Err(std::io::Error::new(std::io::ErrorKind::Interrupted, "scan interrupted, continue it with `scan --resume`"));
+    }
+    if checkpoint_res.is_err() {
+        eprintln!("failed to save checkpoint: {}", checkpoint_res.err().unwrap());
+    }
+    let mut walk_state = walk_state.into_inner().unwrap();
+    if walk_state.spill_error.is_some() {
+        return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("failed to spill scan to disk: {}", walk_state.spill_error.unwrap())));
+    }
+    if let Some(walked) = walk_state.finish_spill()? {
+        if walked.len() == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to do MT walk: Failed to read root path: {:?}", walk_state.errors)))
+        }
+        let run_prefix = format!("{}_curr_run_", root_path_hash);
+        let spilled_scan = SpilledScan {
+            output_path: output_path.clone(),
+            path_to_initial: path_to_initial,
+            path_to_diff: path_to_diff,
+            run_prefix: run_prefix.clone(),
+            limit: flags.maybe_memory_limit.unwrap(),
+            roots: target.roots.clone(),
+            scan_options: scan_options,
+            flags: flags,
+        };
+        let res = finish_spilled_scan(spilled_scan, walked, walk_state.errors, maybe_spilled_prev, diff_file, combined_diffs);
+        if res.is_err() {
+            // The walk's runs stay with the checkpoint for `--resume`, the merge's own are no use to it
+            _ = remove_runs(&output_path, &run_prefix, &vec![]);
+            return res;
+        }
+        checkpoint.remove()?;
+        return res;
+    }
+    let WalkState { entries: mut curr_scan, errors: walk_errors, .. } = walk_state;
+    if curr_scan.len() == 0 {
+        return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to do MT walk: Failed to read root path: {:?}", walk_errors)))
     }
-    let mut curr_scan = maybe_curr_scan.unwrap();
     curr_scan.par_sort_by(|a, b| {
         return a.p.cmp(&b.p);
     });
     
-    // Populate parent map
-    let mut parent_map: HashMap<std::path::PathBuf, usize> = HashMap::new();
-    for ci in 0..curr_scan.len() {
-        let p = &curr_scan[ci].p;
-        parent_map.insert(p.clone(), ci);
-    }
-
-    // Traverse scan in reverse to "bubble up" properties
-    bubble_up_props(&mut curr_scan, &mut parent_map);
-    
-    let initial_scan_exists = exists(&path_to_initial)?;
     if !initial_scan_exists {
-        let f  = File::create(path_to_initial)?;
-        let writer: BufWriter<File> = BufWriter::new(f);
-        bincode::serialize_into(writer, &curr_scan).expect("failed to seralise");
+        bubble_up_props(&mut curr_scan);
+
+        let (num_scan_files, num_scan_dirs) = get_scan_totals(curr_scan.iter().filter(|ent| target.roots.contains(&ent.p)));
+        check_not_interrupted()?;
+        let header = SaveHeader::new(SaveKind::Initial, &target.roots, &scan_options, flags.compression);
+        write_save_file(&path_to_initial, &header, curr_scan.len(), curr_scan.into_iter().map(Ok))?;
+        checkpoint.remove()?;
     
-        return Ok((curr_scan[0].files_here + curr_scan[0].files_below, curr_scan[0].dirs_here + curr_scan[0].dirs_below + 1))
+        return Ok((num_scan_files, num_scan_dirs, walk_errors))
     }
 
-    // Open file
-    let maybe_last_scan = read_save_file(path_to_initial);
-    if maybe_last_scan.is_err() {
-        return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to read entries from file: {:?}", maybe_last_scan.err())))
+    // Directories we couldn't read this time are assumed unchanged, rather than diffed as removed
+    graft_unreadable_subtrees(&mut curr_scan, &initial_scan, &walk_errors);
+
+    bubble_up_props(&mut curr_scan);
+
+    let (num_scan_files, num_scan_dirs) = get_scan_totals(curr_scan.iter().filter(|ent| target.roots.contains(&ent.p)));
+
+    let cache_merged_diffs_changed = diff_file.has_merged_diff != flags.cache_merged_diffs;
+    let entries_before = diff_file.entries.len();
+    let get_move_entries = |from_paths: &HashSet<std::path::PathBuf>| -> Vec<CDirEntry> {
+        return initial_scan.iter().filter(|ent| from_paths.contains(&ent.p)).cloned().collect();
+    };
+    diff_file = diff_saves(diff_file, initial_scan.iter().cloned(), curr_scan.into_iter(), get_move_entries, combined_diffs, flags.min_diff_bytes, flags.cache_merged_diffs);
+    let new_entry_added = diff_file.entries.len() > entries_before;
+    check_not_interrupted()?;
+    if new_entry_added || cache_merged_diffs_changed {
+        write_diff_file(&path_to_diff, &SaveHeader::new(SaveKind::Diffs, &target.roots, &scan_options, flags.compression), diff_file)?;
     }
-    let mut initial_scan: Vec<CDirEntry> = maybe_last_scan.unwrap();
+    checkpoint.remove()?;
 
-    let mut diff_file: DiffFile = DiffFile { has_merged_diff: true, timestamps: vec![], entries: vec![] };
-    let mut combined_diffs: DiffEntry = DiffEntry { diffs: Default::default(), move_to_paths: HashMap::new() };
-    let diff_exists = exists(&path_to_diff)?;
-    if diff_exists {
-        diff_file = read_diff_file(&path_to_diff)?;
-        
-        let res: Result<DiffEntry, Error> = add_combined_diffs(&diff_file, &initial_scan, None, None);
-        if res.is_err() {
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("failed to add combined diffs to scan: {:?}", res.err())))
+    Ok((num_scan_files, num_scan_dirs, walk_errors))
+}
+
+// Where `finish_spilled_scan` reads and writes
+struct SpilledScan {
+    output_path: std::path::PathBuf,
+    path_to_initial: std::path::PathBuf,
+    path_to_diff: std::path::PathBuf,
+    run_prefix: String,
+    limit: usize,
+    roots: Vec<std::path::PathBuf>,
+    scan_options: ScanOptions,
+    flags: ScanFlags,
+}
+
+// Same steps as the end of `scan`, but each one streams the sorted runs and spills its output, so at most about
+// `--memory-limit` of entries are in memory at once
+fn finish_spilled_scan(
+    ss: SpilledScan, 
+    walked: SortedRuns<CDirEntry>, 
+    walk_errors: Vec<WalkError>, 
+    maybe_prev: Option<SortedRuns<CDirEntry>>, 
+    mut diff_file: DiffFile, 
+    combined_diffs: DiffEntry
+) -> std::io::Result<(usize, usize, Vec<WalkError>)> {
+    // Directories we couldn't read this time are assumed unchanged, rather than diffed as removed
+    let mut grafts: Vec<CDirEntry> = vec![];
+    let unreadable: Vec<&WalkError> = walk_errors.iter().filter(|e| e.is_dir_error()).collect();
+    if maybe_prev.is_some() && unreadable.len() > 0 {
+        let mut subtrees: Vec<CDirEntry> = vec![];
+        for res in maybe_prev.as_ref().unwrap().iter()? {
+            let ent = res?;
+            if unreadable.iter().any(|e| ent.p.starts_with(&e.p)) {
+                subtrees.push(ent);
+            }
+        }
+        grafts = get_grafted_entries(&subtrees);
+    }
+    let sources: Vec<Box<dyn Iterator<Item = std::io::Result<CDirEntry>>>> = vec![Box::new(walked.iter()?), Box::new(grafts.into_iter().map(Ok))];
+    let mut curr_sorter = ExternalSorter::new(&ss.output_path, &ss.run_prefix, ss.limit, get_entry_mem_size, |a, b| a.p.cmp(&b.p));
+    bubble_up_props_spilled(MergeIter::new(sources, |a, b| a.p.cmp(&b.p)), &mut curr_sorter)?;
+    let curr = curr_sorter.finish(true)?;
+
+    let mut root_entries: Vec<CDirEntry> = vec![];
+    for res in curr.iter()? {
+        let ent = res?;
+        if ss.roots.contains(&ent.p) {
+            root_entries.push(ent);
+        }
+    }
+    let (num_scan_files, num_scan_dirs) = get_scan_totals(root_entries.iter());
+
+    if maybe_prev.is_none() {
+        check_not_interrupted()?;
+        write_save_file(&ss.path_to_initial, &SaveHeader::new(SaveKind::Initial, &ss.roots, &ss.scan_options, ss.flags.compression), curr.len(), curr.iter()?)?;
+        return Ok((num_scan_files, num_scan_dirs, walk_errors));
+    }
+
+    let prev = maybe_prev.unwrap();
+    let cache_merged_diffs_changed = diff_file.has_merged_diff != ss.flags.cache_merged_diffs;
+    let entries_before = diff_file.entries.len();
+    // `diff_saves` can't fail, a run that can't be read ends its input early and the diff is thrown away
+    let read_err: RefCell<Option<std::io::Error>> = RefCell::new(None);
+    let get_move_entries = |from_paths: &HashSet<std::path::PathBuf>| -> Vec<CDirEntry> {
+        match prev.iter() {
+            Ok(it) => {
+                return UntilErr::new(it, &read_err).filter(|ent| from_paths.contains(&ent.p)).collect();
+            }
+            Err(e) => {
+                *read_err.borrow_mut() = Some(e);
+                return vec![];
+            }
         }
-        combined_diffs = res.unwrap();
+    };
+    diff_file = diff_saves(diff_file, UntilErr::new(prev.iter()?, &read_err), UntilErr::new(curr.iter()?, &read_err), get_move_entries, combined_diffs, ss.flags.min_diff_bytes, ss.flags.cache_merged_diffs);
+    if let Some(e) = read_err.into_inner() {
+        return Err(e);
+    }
+    let new_entry_added = diff_file.entries.len() > entries_before;
+    check_not_interrupted()?;
+    if new_entry_added || cache_merged_diffs_changed {
+        write_diff_file(&ss.path_to_diff, &SaveHeader::new(SaveKind::Diffs, &ss.roots, &ss.scan_options, ss.flags.compression), diff_file)?;
     }
 
+    return Ok((num_scan_files, num_scan_dirs, walk_errors));
+}
+
+// The walk is complete in the checkpoint by now, so resuming only redoes the diff
+fn check_not_interrupted() -> std::io::Result<()> {
+    if checkpoint::is_interrupted() {
+        return Err(std::io::Error::new(std::io::ErrorKind::Interrupted, "scan interrupted before saving, continue it with `scan --resume`"));
+    }
+    return Ok(());
+}
+
+// Turns the initial scan into the state described by `combined_diffs`, keeping it path sorted
+pub fn apply_combined_diffs(scan: &mut Vec<CDirEntry>, combined_diffs: &mut DiffEntry) -> std::io::Result<()> {
     // Apply "moves" before `add_diffs_to_items`
     if combined_diffs.move_to_paths.len() > 0 {
-        for i in 0..initial_scan.len() {
-            let maybe_to_path = combined_diffs.move_to_paths.get(&initial_scan[i].p);
+        for i in 0..scan.len() {
+            let maybe_to_path = get_moved_path(&scan[i].p, &combined_diffs.move_to_paths);
             if maybe_to_path.is_some() {
-                initial_scan[i].p = maybe_to_path.unwrap().to_path_buf();
+                scan[i].p = maybe_to_path.unwrap();
             }
         }
     }
 
     // TODO: This is VERY dumb, there should be a faster way to do this
-    let res = add_diffs_to_items::<CDirEntry, CDirEntryDiff>(&mut initial_scan, &mut combined_diffs.diffs, |a, b| {
+    let res = add_diffs_to_items::<CDirEntry, CDirEntryDiff>(scan, &mut combined_diffs.diffs, |a, b| {
         return a.p.cmp(&b.p);
     }, |it, d| {
         return it.p == d.p;
@@ -80,24 +444,133 @@ pub fn scan(target_path: std::path::PathBuf, output_path: std::path::PathBuf, mi
     }
 
     // Step above probably screwed up the order...
-    initial_scan.par_sort_by(|a, b| {
+    scan.par_sort_by(|a, b| {
         return a.p.cmp(&b.p);
     });
 
-    let num_scan_files = curr_scan[0].files_here + curr_scan[0].files_below;
-    let num_scan_dirs = curr_scan[0].dirs_here + curr_scan[0].dirs_below + 1;
+    return Ok(());
+}
 
-    let cache_merged_diffs_changed = diff_file.has_merged_diff != cache_merged_diffs;
-    let entries_before = diff_file.entries.len();
-    diff_file = diff_saves(diff_file, initial_scan, curr_scan, combined_diffs, min_diff_bytes, cache_merged_diffs);
-    let new_entry_added = diff_file.entries.len() > entries_before;
-    if new_entry_added || cache_merged_diffs_changed {
-        let f  = File::create(path_to_diff)?;
-        let writer = BufWriter::new(f);
-        bincode::serialize_into(writer, &diff_file).expect("failed to seralise");
+// Diffs only have the top directory of a move, its subtree moves with it
+fn get_moved_path(p: &std::path::PathBuf, move_to_paths: &HashMap<std::path::PathBuf, std::path::PathBuf>) -> Option<std::path::PathBuf> {
+    for from in p.ancestors() {
+        let Some(to) = move_to_paths.get(from) else { continue };
+        if from == p {
+            return Some(to.clone());
+        }
+        return Some(to.join(p.strip_prefix(from).unwrap()));
+    }
+    return None;
+}
+
+// Same as `apply_combined_diffs` for an initial scan that's read one entry at a time, the result is sorted by `out`
+fn apply_combined_diffs_spilled(initial: impl Iterator<Item = std::io::Result<CDirEntry>>, combined_diffs: &DiffEntry, out: &mut ExternalSorter<CDirEntry>) -> std::io::Result<()> {
+    let removed: HashSet<&std::path::PathBuf> = combined_diffs.diffs[REM_DT_IDX].iter().filter(|d| !ignore_dir_entry(d)).map(|d| &d.p).collect();
+    let modified: HashMap<&std::path::PathBuf, &CDirEntryDiff> = combined_diffs.diffs[MOD_DT_IDX].iter().filter(|d| !ignore_dir_entry(d)).map(|d| (&d.p, d)).collect();
+    for res in initial {
+        let mut ent = res?;
+        if let Some(to_path) = get_moved_path(&ent.p, &combined_diffs.move_to_paths) {
+            ent.p = to_path;
+        }
+        if removed.contains(&ent.p) {
+            continue;
+        }
+        if let Some(d) = modified.get(&ent.p) {
+            merge_dir_diff_to_entry(&mut ent, (*d).clone());
+        }
+        out.push(ent)?;
+    }
+    for d in &combined_diffs.diffs[ADD_DT_IDX] {
+        if !ignore_dir_entry(d) {
+            out.push(get_entry_from_dir_diff(d.clone()))?;
+        }
+    }
+    return Ok(());
+}
+
+pub fn print_walk_error_summary(errors: &Vec<WalkError>) {
+    const MAX_LISTED_ERRORS: usize = 20;
+    if errors.len() == 0 {
+        return;
+    }
+
+    let num_dir_errors = errors.iter().filter(|e| e.is_dir_error()).count();
+    eprintln!("{} paths could not be read ({} directories, {} files/entries):", errors.len(), num_dir_errors, errors.len() - num_dir_errors);
+    for e in errors.iter().take(MAX_LISTED_ERRORS) {
+        let reason = match e.errno {
+            Some(errno) => format!("{}", Error::from_raw_os_error(errno)),
+            None => String::from("unknown error"),
+        };
+        eprintln!("ERR: {:?} ({}: {})", e.p, e.phase, reason);
+    }
+    if errors.len() > MAX_LISTED_ERRORS {
+        eprintln!("... and {} more", errors.len() - MAX_LISTED_ERRORS);
+    }
+}
+
+fn graft_unreadable_subtrees(curr_scan: &mut Vec<CDirEntry>, prev_scan: &Vec<CDirEntry>, errors: &Vec<WalkError>) {
+    let mut num_grafted = 0;
+    for e in errors {
+        if !e.is_dir_error() {
+            continue;
+        }
+
+        // `prev_scan` is path sorted, so the unreadable directory's subtree is contiguous
+        let start_idx = prev_scan.partition_point(|ent| ent.p < e.p);
+        let num_in_subtree = prev_scan[start_idx..].iter().take_while(|ent| ent.p.starts_with(&e.p)).count();
+        curr_scan.extend(get_grafted_entries(&prev_scan[start_idx..start_idx + num_in_subtree]));
+        num_grafted += num_in_subtree;
+    }
+
+    if num_grafted > 0 {
+        curr_scan.par_sort_by(|a, b| {
+            return a.p.cmp(&b.p);
+        });
+    }
+}
+
+// Files and directories under the roots, from their (bubbled up) entries
+fn get_scan_totals<'a>(root_entries: impl Iterator<Item = &'a CDirEntry>) -> (usize, usize) {
+    let mut totals = (0, 0);
+    for ent in root_entries {
+        totals.0 += ent.files_here + ent.files_below;
+        totals.1 += ent.dirs_here + ent.dirs_below + 1;
+    }
+    return totals;
+}
+
+// Totals below are recalculated by bubbling up, so only what was found in the directories themselves is kept. `entries` are
+// path sorted, so a directory with entries below it is directly followed by one. Directories at `--max-depth` have none,
+// their subdirectory counts were rolled up by the walk and are kept
+fn get_grafted_entries(entries: &[CDirEntry]) -> Vec<CDirEntry> {
+    let mut ret = Vec::with_capacity(entries.len());
+    for i in 0..entries.len() {
+        let has_entries_below = i + 1 < entries.len() && entries[i + 1].p.starts_with(&entries[i].p);
+        ret.push(get_grafted_entry(entries[i].clone(), has_entries_below));
     }
+    return ret;
+}
+
+fn get_grafted_entry(mut ent: CDirEntry, has_entries_below: bool) -> CDirEntry {
+    if has_entries_below {
+        ent.dirs_here = 0;
+        ent.dirs_below = 0;
+    }
+    ent.files_below = 0;
+    ent.size_below = 0;
+    ent.unique_size_below = 0;
+    ent.alloc_below = 0;
+    ent.specials_below = 0;
+    return ent;
+}
 
-    Ok((num_scan_files, num_scan_dirs))
+// The number of diffs (not counting a cached merged diff) from scans before `t`, they're the first entries of the file
+pub fn get_num_diffs_before(diff_file: &DiffFile, t: SystemTime) -> usize {
+    let mut end_range = diff_file.timestamps.len().min(diff_file.entries.len());
+    if diff_file.has_merged_diff && end_range > 0 {
+        end_range -= 1;
+    }
+    return diff_file.timestamps[..end_range].iter().take_while(|ts| **ts < t).count();
 }
 
 pub fn add_combined_diffs(diff_file: &DiffFile, full_scan_entries: &Vec<CDirEntry>, maybe_start_diff_time: Option<SystemTime>, maybe_end_diff_time: Option<SystemTime>) -> std::io::Result<DiffEntry> {
@@ -120,15 +593,10 @@ pub fn add_combined_diffs(diff_file: &DiffFile, full_scan_entries: &Vec<CDirEntr
         end_range -= 1;
     }
     if is_diff_range_restricted {
-        for i in 0..end_range {
-            let modified_at = diff_file.timestamps[i];
-            if start_idx < 0 && modified_at >= maybe_start_diff_time.unwrap() {
-                start_idx = i as i32;
-            } else if end_idx < 0 && modified_at > maybe_end_diff_time.unwrap() {
-                end_idx = (i - 1) as i32;
-                break;
-            }
-        }
+        // Either end of the range can be left open, the diff of a scan at exactly the end time is included
+        start_idx = maybe_start_diff_time.map_or(0, |t| get_num_diffs_before(diff_file, t)) as i32;
+        end_idx = maybe_end_diff_time.map_or(end_range, |t| diff_file.timestamps[..end_range].iter().take_while(|ts| **ts <= t).count()) as i32;
+        end_idx = end_idx.max(start_idx);
     }
     if start_idx < 0 {
         start_idx = 0;
@@ -152,22 +620,157 @@ pub fn add_combined_diffs(diff_file: &DiffFile, full_scan_entries: &Vec<CDirEntr
     return Ok(combined_diffs);
 }
 
-pub fn bubble_up_props(scan: &mut Vec<CDirEntry>, pm: &mut HashMap<std::path::PathBuf, usize>) {
-    // Traverse scan in reverse to "bubble up" properties
-    if scan.len() > 0 {
-        for i in 0..scan.len() {
-            // Calculate memory usage for self
-            let curr_idx = scan.len() - 1 - i;
-            if let Some(parent) = scan[curr_idx].p.parent() {
-                if let Some(maybe_ent) = &pm.get(parent) {
-                    let idx = *maybe_ent;
-    
-                    scan[*idx].dirs_here += 1;
-                    scan[*idx].dirs_below += scan[curr_idx].dirs_here + scan[curr_idx].dirs_below;
-                    scan[*idx].files_below += scan[curr_idx].files_here + scan[curr_idx].files_below;
-                    scan[*idx].size_below += scan[curr_idx].size_here + scan[curr_idx].size_below;
-                }
+// Same as `bubble_up_props` for path sorted entries that are read one at a time. A directory's subtree directly follows it,
+// so only the directories from the root to the current entry are kept, each is finished (and written to `out`) once the
+// entries move past its subtree
+fn bubble_up_props_spilled(entries: impl Iterator<Item = std::io::Result<CDirEntry>>, out: &mut ExternalSorter<CDirEntry>) -> std::io::Result<()> {
+    let mut seen = SeenInodes::default();
+    let mut stack: Vec<CDirEntry> = vec![];
+    for res in entries {
+        let mut ent = res?;
+        set_unique_size_here(&mut ent, &mut seen);
+
+        while stack.len() > 0 && !ent.p.starts_with(&stack[stack.len() - 1].p) {
+            finish_bubbled_entry(&mut stack, out)?;
+        }
+        stack.push(ent);
+    }
+    while stack.len() > 0 {
+        finish_bubbled_entry(&mut stack, out)?;
+    }
+    return Ok(());
+}
+
+fn finish_bubbled_entry(stack: &mut Vec<CDirEntry>, out: &mut ExternalSorter<CDirEntry>) -> std::io::Result<()> {
+    let ent = stack.pop().unwrap();
+    if let Some(parent) = stack.last_mut() {
+        add_to_parent(parent, &ent);
+    }
+    return out.push(ent);
+}
+
+// Adds a finished directory's totals to its parent's, `parent` is the closest directory above it that was scanned
+fn add_to_parent(parent: &mut CDirEntry, ent: &CDirEntry) {
+    if ent.p.parent() != Some(parent.p.as_path()) {
+        return;
+    }
+    parent.dirs_here += 1;
+    parent.dirs_below += ent.dirs_here + ent.dirs_below;
+    parent.files_below += ent.files_here + ent.files_below;
+    parent.size_below += ent.size_here + ent.size_below;
+    parent.unique_size_below += ent.unique_size_here + ent.unique_size_below;
+    parent.alloc_below += ent.alloc_here + ent.alloc_below;
+    parent.specials_below += ent.specials_here + ent.specials_below;
+}
+
+// Inodes already counted towards the unique sizes of the current root. Each root of a profile counts its own, so its unique
+// size doesn't depend on the other roots
+#[derive(Default)]
+struct SeenInodes {
+    maybe_root: Option<std::path::PathBuf>,
+    inodes: HashSet<(u64, u64)>,
+}
+
+// Hard linked files count towards the unique size of the first directory (in path order) of their root they're found in.
+// Sizes rolled up from below `--max-depth` have no file entries, so they're counted as unique
+fn set_unique_size_here(ent: &mut CDirEntry, seen: &mut SeenInodes) {
+    // Entries are path sorted, so each root's subtree is contiguous and starts with the root
+    if seen.maybe_root.is_none() || !ent.p.starts_with(seen.maybe_root.as_ref().unwrap()) {
+        seen.maybe_root = Some(ent.p.clone());
+        seen.inodes.clear();
+    }
+    let listed_size: i64 = ent.files.iter().chain(ent.symlinks.iter()).map(|f| f.sz as i64).sum();
+    ent.unique_size_here = ent.size_here - listed_size;
+    for f in ent.files.iter().chain(ent.symlinks.iter()) {
+        if f.nlink > 1 && !seen.inodes.insert((f.dev, f.ino)) {
+            continue;
+        }
+        ent.unique_size_here += f.sz as i64;
+    }
+}
+
+// `scan` is path sorted, so like `bubble_up_props_spilled` only the indexes of the directories from the root to the current
+// entry are kept, rather than a map of every path
+pub fn bubble_up_props(scan: &mut Vec<CDirEntry>) {
+    let mut seen = SeenInodes::default();
+    for ent in scan.iter_mut() {
+        set_unique_size_here(ent, &mut seen);
+    }
+
+    let mut stack: Vec<usize> = vec![];
+    for i in 0..=scan.len() {
+        while let Some(&top) = stack.last() {
+            if i < scan.len() && scan[i].p.starts_with(&scan[top].p) {
+                break;
+            }
+            stack.pop();
+            if let Some(&parent_idx) = stack.last() {
+                // Parents are always before their children
+                let (before, after) = scan.split_at_mut(top);
+                add_to_parent(&mut before[parent_idx], &after[0]);
             }
         }
+        if i < scan.len() {
+            stack.push(i);
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use crate::walk::{FileEntry, WalkPhase};
+
+    fn get_linked_file(bn: &str, ino: u64) -> FileEntry {
+        return FileEntry { bn: std::ffi::OsString::from(bn), sz: 100, alloc_sz: 4096, dev: 1, ino: ino, nlink: 2, ..Default::default() };
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn bubble_up_props_counts_links_once_per_root() {
+        // Both links in one root, the first directory in path order has the inode
+        let mut scan = vec![
+            CDirEntry::new_for_test("/p", vec![]),
+            CDirEntry::new_for_test("/p/a", vec![get_linked_file("f", 7)]),
+            CDirEntry::new_for_test("/p/b", vec![get_linked_file("g", 7)]),
+        ];
+        bubble_up_props(&mut scan);
+        assert_eq!(scan[1].unique_size_here, 100);
+        assert_eq!(scan[2].unique_size_here, 0);
+        assert_eq!(scan[0].size_below, 200);
+        assert_eq!(scan[0].unique_size_below, 100);
+
+        // The links are in two roots of a profile, each root counts it
+        let mut scan = vec![
+            CDirEntry::new_for_test("/p/a", vec![get_linked_file("f", 7)]),
+            CDirEntry::new_for_test("/p/b", vec![get_linked_file("g", 7)]),
+            CDirEntry::new_for_test("/p/b/c", vec![get_linked_file("h", 7)]),
+        ];
+        bubble_up_props(&mut scan);
+        assert_eq!(scan[0].unique_size_here, 100);
+        assert_eq!(scan[1].unique_size_here, 100);
+        assert_eq!(scan[2].unique_size_here, 0);
+        assert_eq!(scan[1].unique_size_below, 0);
+    }
+
+    // `/r/u` couldn't be read, its subtree from the previous scan ends in a directory at `--max-depth` with 3 subdirectories
+    // and 5 more below them rolled up
+    #[test]
+    fn graft_unreadable_subtrees_keeps_rolled_up_dir_counts() {
+        let mut prev_scan = vec![
+            CDirEntry::new_for_test("/r", vec![]),
+            CDirEntry::new_for_test("/r/u", vec![]),
+            CDirEntry::new_for_test("/r/u/leaf", vec![]),
+        ];
+        prev_scan[2].dirs_here = 3;
+        prev_scan[2].dirs_below = 5;
+        bubble_up_props(&mut prev_scan);
+        assert_eq!((prev_scan[0].dirs_here, prev_scan[0].dirs_below), (1, 9));
+
+        let mut curr_scan = vec![CDirEntry::new_for_test("/r", vec![])];
+        let errors = vec![WalkError { p: PathBuf::from("/r/u"), errno: Some(13), phase: WalkPhase::ReadDir }];
+        graft_unreadable_subtrees(&mut curr_scan, &prev_scan, &errors);
+        bubble_up_props(&mut curr_scan);
+        let counts: Vec<(usize, usize)> = curr_scan.iter().map(|ent| (ent.dirs_here, ent.dirs_below)).collect();
+        assert_eq!(counts, vec![(1, 9), (1, 8), (3, 5)]);
+    }
+}
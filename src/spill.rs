@@ -0,0 +1,335 @@
+use std::{cell::RefCell, cmp::Ordering, collections::BinaryHeap, fs::File, io::{BufReader, BufWriter, Write}, marker::PhantomData, path::PathBuf};
+use rayon::slice::ParallelSliceMut;
+use serde::{de::DeserializeOwned, Serialize};
+use crate::walk::{CDirEntry, FileEntry};
+
+// Rough heap + inline size of an entry, used to decide when to spill
+pub fn get_entry_mem_size(e: &CDirEntry) -> usize {
+    let mut sz = std::mem::size_of::<CDirEntry>() + e.p.as_os_str().len();
+    for f in e.files.iter().chain(e.symlinks.iter()).chain(e.specials.iter()) {
+        sz += std::mem::size_of::<FileEntry>() + f.bn.len();
+        if let Some(t) = &f.target {
+            sz += t.as_os_str().len();
+        }
+    }
+    return sz;
+}
+
+// Removes run files left behind by a crashed scan
+pub fn remove_runs(dir: &PathBuf, prefix: &str, keep: &Vec<PathBuf>) -> std::io::Result<()> {
+    for maybe_ent in std::fs::read_dir(dir)? {
+        let p = maybe_ent?.path();
+        let is_run = p.file_name().map(|n| n.to_string_lossy().starts_with(prefix)).unwrap_or(false);
+        if is_run && !keep.contains(&p) {
+            std::fs::remove_file(&p)?;
+        }
+    }
+    return Ok(());
+}
+
+// Collects items into sorted runs on disk once they use more than `limit` bytes, `finish` merges them back in order
+pub struct ExternalSorter<T> {
+    dir: PathBuf,
+    prefix: String,
+    limit: usize,
+    size_of: fn(&T) -> usize,
+    cmp: fn(&T, &T) -> Ordering,
+    buf: Vec<T>,
+    buf_bytes: usize,
+    runs: Vec<PathBuf>,
+    num_in_runs: usize,
+}
+impl<T: Serialize + DeserializeOwned + Clone + Send> ExternalSorter<T> {
+    pub fn new(dir: &PathBuf, prefix: &str, limit: usize, size_of: fn(&T) -> usize, cmp: fn(&T, &T) -> Ordering) -> Self {
+        return ExternalSorter {
+            dir: dir.clone(),
+            prefix: prefix.to_string(),
+            limit: limit,
+            size_of: size_of,
+            cmp: cmp,
+            buf: vec![],
+            buf_bytes: 0,
+            runs: vec![],
+            num_in_runs: 0,
+        };
+    }
+
+    // Continues from runs written before, e.g. by an interrupted scan
+    pub fn with_runs(mut self, runs: Vec<PathBuf>, num_in_runs: usize) -> Self {
+        self.runs = runs;
+        self.num_in_runs = num_in_runs;
+        return self;
+    }
+
+    pub fn runs(&self) -> &Vec<PathBuf> {
+        return &self.runs;
+    }
+
+    pub fn num_in_runs(&self) -> usize {
+        return self.num_in_runs;
+    }
+
+    pub fn limit(&self) -> usize {
+        return self.limit;
+    }
+
+    pub fn push(&mut self, v: T) -> std::io::Result<()> {
+        self.buf_bytes += (self.size_of)(&v);
+        self.buf.push(v);
+        if self.buf_bytes > self.limit {
+            let buf = std::mem::take(&mut self.buf);
+            self.buf_bytes = 0;
+            return self.write_run(buf);
+        }
+        return Ok(());
+    }
+
+    // Sorts `items` and writes them as a new run
+    pub fn write_run(&mut self, mut items: Vec<T>) -> std::io::Result<()> {
+        if items.len() == 0 {
+            return Ok(());
+        }
+        let cmp = self.cmp;
+        items.par_sort_by(|a, b| cmp(a, b));
+
+        let p = self.dir.join(format!("{}{}", self.prefix, self.runs.len()));
+        let mut writer = BufWriter::new(File::create(&p)?);
+        let res = bincode::serialize_into(&mut writer, &(items.len() as u64));
+        if res.is_err() {
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("failed to write run {:?}: {}", p, res.err().unwrap())));
+        }
+        for it in &items {
+            let res = bincode::serialize_into(&mut writer, it);
+            if res.is_err() {
+                return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("failed to write run {:?}: {}", p, res.err().unwrap())));
+            }
+        }
+        writer.flush()?;
+        writer.get_ref().sync_data()?;
+
+        self.num_in_runs += items.len();
+        self.runs.push(p);
+        return Ok(());
+    }
+
+    // Writes what's left in memory as a last run, so nothing but the merge buffers stay in memory
+    pub fn finish(mut self, remove_on_drop: bool) -> std::io::Result<SortedRuns<T>> {
+        let buf = std::mem::take(&mut self.buf);
+        self.write_run(buf)?;
+        return Ok(SortedRuns {
+            runs: self.runs,
+            len: self.num_in_runs,
+            cmp: self.cmp,
+            remove_on_drop: remove_on_drop,
+        });
+    }
+}
+
+// Sorted items spread over sorted runs, which are merged as they're read. Can be iterated more than once
+pub struct SortedRuns<T> {
+    runs: Vec<PathBuf>,
+    len: usize,
+    cmp: fn(&T, &T) -> Ordering,
+    remove_on_drop: bool,
+}
+impl<T: DeserializeOwned> SortedRuns<T> {
+    pub fn len(&self) -> usize {
+        return self.len;
+    }
+
+    pub fn iter(&self) -> std::io::Result<MergeIter<'_, T>> {
+        let mut sources: Vec<Box<dyn Iterator<Item = std::io::Result<T>> + '_>> = Vec::with_capacity(self.runs.len());
+        for p in &self.runs {
+            sources.push(Box::new(RunReader::<T>::open(p)?));
+        }
+        return Ok(MergeIter::new(sources, self.cmp));
+    }
+}
+impl<T> Drop for SortedRuns<T> {
+    fn drop(&mut self) {
+        if !self.remove_on_drop {
+            return;
+        }
+        for p in &self.runs {
+            _ = std::fs::remove_file(p);
+        }
+    }
+}
+
+// Reads back a run written by `ExternalSorter::write_run`
+struct RunReader<T> {
+    reader: BufReader<File>,
+    remaining: u64,
+    p: PathBuf,
+    _t: PhantomData<T>,
+}
+impl<T: DeserializeOwned> RunReader<T> {
+    fn open(p: &PathBuf) -> std::io::Result<Self> {
+        let mut reader = BufReader::new(File::open(p)?);
+        let maybe_len: Result<u64, _> = bincode::deserialize_from(&mut reader);
+        if maybe_len.is_err() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("failed to read run {:?}: {}", p, maybe_len.err().unwrap())));
+        }
+        return Ok(RunReader {
+            reader: reader,
+            remaining: maybe_len.unwrap(),
+            p: p.clone(),
+            _t: PhantomData,
+        });
+    }
+}
+impl<T: DeserializeOwned> Iterator for RunReader<T> {
+    type Item = std::io::Result<T>;
+
+    fn next(&mut self) -> Option<std::io::Result<T>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        // Runs were fully written and synced before being read, but the run can still be truncated or the disk broken
+        let res: Result<T, _> = bincode::deserialize_from(&mut self.reader);
+        match res {
+            Ok(v) => Some(Ok(v)),
+            Err(e) => {
+                // Nothing after a bad item can be read
+                self.remaining = 0;
+                return Some(Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("failed to read run {:?}: {}", self.p, e))));
+            }
+        }
+    }
+}
+
+struct HeapItem<T> {
+    v: T,
+    src: usize,
+    cmp: fn(&T, &T) -> Ordering,
+}
+impl<T> PartialEq for HeapItem<T> {
+    fn eq(&self, other: &Self) -> bool {
+        return self.cmp(other) == Ordering::Equal;
+    }
+}
+impl<T> Eq for HeapItem<T> {}
+impl<T> PartialOrd for HeapItem<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        return Some(self.cmp(other));
+    }
+}
+impl<T> Ord for HeapItem<T> {
+    // Reversed, `BinaryHeap` is a max heap. Ties go to the earlier source to keep the merge stable
+    fn cmp(&self, other: &Self) -> Ordering {
+        return (self.cmp)(&other.v, &self.v).then(other.src.cmp(&self.src));
+    }
+}
+
+// k-way merge of sorted iterators. A source's error is passed on in place of the rest of the merge, there's no order to
+// keep once a source is missing items
+pub struct MergeIter<'a, T> {
+    sources: Vec<Box<dyn Iterator<Item = std::io::Result<T>> + 'a>>,
+    heap: BinaryHeap<HeapItem<T>>,
+    maybe_err: Option<std::io::Error>,
+}
+impl<'a, T> MergeIter<'a, T> {
+    pub fn new(mut sources: Vec<Box<dyn Iterator<Item = std::io::Result<T>> + 'a>>, cmp: fn(&T, &T) -> Ordering) -> Self {
+        let mut heap = BinaryHeap::with_capacity(sources.len());
+        let mut maybe_err = None;
+        for (i, s) in sources.iter_mut().enumerate() {
+            match s.next() {
+                Some(Ok(v)) => {
+                    heap.push(HeapItem { v: v, src: i, cmp: cmp });
+                }
+                Some(Err(e)) => {
+                    maybe_err = Some(e);
+                    break;
+                }
+                None => {}
+            }
+        }
+        return MergeIter {
+            sources: sources,
+            heap: heap,
+            maybe_err: maybe_err,
+        };
+    }
+}
+impl<'a, T> Iterator for MergeIter<'a, T> {
+    type Item = std::io::Result<T>;
+
+    fn next(&mut self) -> Option<std::io::Result<T>> {
+        if let Some(e) = self.maybe_err.take() {
+            self.heap.clear();
+            return Some(Err(e));
+        }
+        let top = self.heap.pop()?;
+        match self.sources[top.src].next() {
+            Some(Ok(v)) => {
+                self.heap.push(HeapItem { v: v, src: top.src, cmp: top.cmp });
+            }
+            Some(Err(e)) => {
+                self.maybe_err = Some(e);
+            }
+            None => {}
+        }
+        return Some(Ok(top.v));
+    }
+}
+
+// Passes on the items of a fallible iterator to code that can't fail, e.g. `diff_saves`. Stops at the first error of any
+// iterator sharing `err`, the caller returns it once that code is done
+pub struct UntilErr<'e, I> {
+    iter: I,
+    err: &'e RefCell<Option<std::io::Error>>,
+}
+impl<'e, I> UntilErr<'e, I> {
+    pub fn new(iter: I, err: &'e RefCell<Option<std::io::Error>>) -> Self {
+        return UntilErr {
+            iter: iter,
+            err: err,
+        };
+    }
+}
+impl<'e, T, I: Iterator<Item = std::io::Result<T>>> Iterator for UntilErr<'e, I> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        if self.err.borrow().is_some() {
+            return None;
+        }
+        match self.iter.next()? {
+            Ok(v) => {
+                return Some(v);
+            }
+            Err(e) => {
+                *self.err.borrow_mut() = Some(e);
+                return None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncated_run_is_an_error() {
+        let dir = std::env::temp_dir().join(format!("seye_rs_test_truncated_run_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut sorter: ExternalSorter<u64> = ExternalSorter::new(&dir, "run_", 1, |_| 8, |a, b| a.cmp(b));
+        sorter.write_run(vec![1, 3, 5, 7]).unwrap();
+        sorter.write_run(vec![2, 4, 6, 8]).unwrap();
+        let runs = sorter.finish(true).unwrap();
+
+        // Cut the second run in the middle of its third item
+        let p = &runs.runs[1];
+        let len = std::fs::metadata(p).unwrap().len();
+        File::options().write(true).open(p).unwrap().set_len(len - 12).unwrap();
+
+        let merged: Vec<std::io::Result<u64>> = runs.iter().unwrap().collect();
+        let read: Vec<u64> = merged.iter().take_while(|res| res.is_ok()).map(|res| *res.as_ref().unwrap()).collect();
+        assert_eq!(read, vec![1, 2, 3, 4]);
+        assert!(merged.last().unwrap().is_err());
+        drop(runs);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
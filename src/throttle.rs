@@ -0,0 +1,89 @@
+use std::{io::{Error, ErrorKind}, sync::Mutex, time::{Duration, Instant}};
+
+// `ioprio_set(2)` constants, they aren't in `libc`
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+const IOPRIO_CLASS_BE: libc::c_int = 2;
+const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+const IOPRIO_BE_LOWEST_LEVEL: libc::c_int = 7;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IoClass {
+    // Only gets disk time when no other process wants it
+    Idle,
+    // The lowest priority of the default class
+    BestEffort,
+}
+impl IoClass {
+    fn get_ioprio(&self) -> libc::c_int {
+        match self {
+            IoClass::Idle => {
+                return IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT;
+            }
+            IoClass::BestEffort => {
+                return (IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | IOPRIO_BE_LOWEST_LEVEL;
+            }
+        }
+    }
+}
+
+// CPU and IO priority of the scan threads
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Priority {
+    pub maybe_nice: Option<i32>,
+    pub maybe_io_class: Option<IoClass>,
+}
+impl Priority {
+    // Linux keeps the nice value and IO priority per thread, so this only changes the calling thread (and the threads it
+    // starts after)
+    pub fn apply_to_current_thread(&self) -> std::io::Result<()> {
+        if let Some(nice) = self.maybe_nice {
+            let res = unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) };
+            if res != 0 {
+                return Err(Error::new(ErrorKind::Other, format!("failed to set nice value to {}: {}", nice, Error::last_os_error())));
+            }
+        }
+        if let Some(io_class) = self.maybe_io_class {
+            let res = unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, io_class.get_ioprio()) };
+            if res != 0 {
+                return Err(Error::new(ErrorKind::Other, format!("failed to set IO priority to {:?}: {}", io_class, Error::last_os_error())));
+            }
+        }
+        return Ok(());
+    }
+}
+
+// Token bucket shared by all the walk threads, every `read_dir` and `metadata` call takes a token. Up to a second of
+// unused tokens are kept, so short bursts aren't slowed down
+pub struct RateLimiter {
+    per_sec: f64,
+    // Tokens left and when they were last topped up
+    state: Mutex<(f64, Instant)>,
+}
+impl RateLimiter {
+    pub fn new(per_sec: u64) -> Self {
+        return RateLimiter {
+            per_sec: per_sec as f64,
+            state: Mutex::new((per_sec as f64, Instant::now())),
+        };
+    }
+
+    // Blocks until a token is available
+    pub fn take(&self) {
+        loop {
+            let wait_secs;
+            {
+                let mut state = self.state.lock().unwrap();
+                let now = Instant::now();
+                state.0 = f64::min(self.per_sec, state.0 + now.duration_since(state.1).as_secs_f64() * self.per_sec);
+                state.1 = now;
+                if state.0 >= 1.0 {
+                    state.0 -= 1.0;
+                    return;
+                }
+                wait_secs = (1.0 - state.0) / self.per_sec;
+            }
+            std::thread::sleep(Duration::from_secs_f64(wait_secs));
+        }
+    }
+}
@@ -1,7 +1,6 @@
-use std::path::PathBuf;
+use std::{collections::{HashMap, HashSet}, fs::File, io::{Read, Seek, SeekFrom}, os::unix::{ffi::OsStringExt, fs::MetadataExt}, path::PathBuf, sync::Mutex};
 use chrono;
-use rayon::iter::{IntoParallelRefMutIterator, ParallelIterator};
-use crate::walk::{walk_collect_until_limit, CDirEntry};
+use crate::{checkpoint::{self, WalkState}, throttle::Priority, walk::{walk_collect_until_limit, CDirEntry, HashMode, WalkOptions}};
 use chksum_md5 as md5;
 
 pub const KILOBYTE: usize = 1024;
@@ -59,69 +58,229 @@ pub fn get_bytes_from_arg(a: &String) -> std::io::Result<usize> {
     Ok(ret)
 }
 
-pub fn collect_from_root(
-    root: PathBuf, 
-    num_threads: usize, 
+// Translates a shell style glob into an anchored regex: `*` and `?` stay within a path component, `**` crosses them
+pub fn glob_to_regex(glob: &str) -> String {
+    let mut re = String::from("^");
+    let chars: Vec<char> = glob.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            '*' => {
+                if i + 1 < chars.len() && chars[i + 1] == '*' {
+                    re.push_str(".*");
+                    i += 1;
+                } else {
+                    re.push_str("[^/]*");
+                }
+            }
+            '?' => {
+                re.push_str("[^/]");
+            }
+            '[' => {
+                // Character classes are passed through as is, unless they're never closed
+                let maybe_close = chars[i + 1..].iter().position(|c| *c == ']');
+                match maybe_close {
+                    Some(close_off) => {
+                        let class: String = chars[i + 1..i + 1 + close_off].iter().collect();
+                        re.push('[');
+                        re.push_str(&class.replacen('!', "^", if class.starts_with('!') {1} else {0}));
+                        re.push(']');
+                        i += close_off + 1;
+                    }
+                    None => {
+                        re.push_str("\\[");
+                    }
+                }
+            }
+            _ => {
+                re.push_str(&regex::escape(&c.to_string()));
+            }
+        }
+        i += 1;
+    }
+    re.push('$');
+    return re;
+}
+
+// Walks every pending dir in `state` on the current rayon pool (see `build_thread_pool`), until they're all walked or
+// the scan is interrupted
+pub fn collect_pending(
+    state: &Mutex<WalkState>,
     num_thread_iterations_before_yield: usize,
-) -> std::io::Result<Vec<CDirEntry>> {
-    let mut res: Vec<CDirEntry> = Vec::new();
-
-    // Do first pass of thread_*_fn() on root to get multiple items
-    let mut initial_dirs = vec![root];
-    let maybe_initial_paths: std::io::Result<Vec<PathBuf>> = walk_collect_until_limit(&mut initial_dirs,&mut res, num_thread_iterations_before_yield);
-    let Ok(mut paths_to_distribute) = maybe_initial_paths else {
-        return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to read root path: {:?}", maybe_initial_paths.err())))
-    };
-    
-    // Spin up threads to, iterate over items and inform main if: they have excess paths to return OR they're done
-    loop {
-        // Redistribute paths
-        let mut curr_num_threads = num_threads;
-        if paths_to_distribute.len() < curr_num_threads {
-            curr_num_threads = paths_to_distribute.len();
+    opts: &WalkOptions,
+) {
+    let roots: Vec<PathBuf> = state.lock().unwrap().pending.iter().cloned().collect();
+    if let Some(p) = opts.progress {
+        p.add_queued(roots.len() as isize);
+    }
+
+    // No rounds: each task walks a batch then spawns its leftover dirs onto its own deque, idle threads steal from
+    // the others' deques, so a deep subtree never holds up the rest of the walk
+    rayon::scope(|s| {
+        for root in roots {
+            s.spawn(move |s| {
+                walk_and_spawn(s, root, state, opts, num_thread_iterations_before_yield);
+            });
         }
-        let mut paths_per_thread = distribute_paths_per_thread(&mut paths_to_distribute, curr_num_threads);
-
-        // Start "walk" on auxiliary threads
-        let new_dirs_and_results: (Vec<Vec<PathBuf>>, Vec<Vec<CDirEntry>>) = paths_per_thread.par_iter_mut().map(|p| {
-            let mut new_entries = vec![];
-            let Ok(leftover_paths) = walk_collect_until_limit(p, &mut new_entries, num_thread_iterations_before_yield)
-            else {
-                return (vec![], vec![]);
-            };
-            return (leftover_paths, new_entries);
-        }).unzip();
-
-        // Retrieve paths to distribute and add to all_results    
-        paths_to_distribute = new_dirs_and_results.0.into_iter().flatten().collect();
-        res.append(&mut new_dirs_and_results.1.into_iter().flatten().collect());
-        if paths_to_distribute.len() == 0 {
-            break;
+    });
+}
+
+fn walk_and_spawn<'s, 'a: 's>(
+    s: &rayon::Scope<'s>, 
+    p: PathBuf, 
+    state: &'s Mutex<WalkState>, 
+    opts: &'s WalkOptions<'a>, 
+    num_thread_iterations_before_yield: usize
+) {
+    // Interrupted tasks leave their dir pending, to be walked on resume
+    if checkpoint::is_interrupted() {
+        return;
+    }
+
+    let mut paths = vec![p.clone()];
+    let mut new_entries = vec![];
+    let mut new_errors = vec![];
+    let leftover_paths = walk_collect_until_limit(&mut paths, &mut new_entries, &mut new_errors, opts, num_thread_iterations_before_yield);
+    {
+        // Results and pending dirs are swapped under the same lock, so a checkpoint never sees one without the other
+        state.lock().unwrap().add_results(new_entries, new_errors, &p, &leftover_paths);
+    }
+
+    for lp in leftover_paths {
+        s.spawn(move |s| {
+            walk_and_spawn(s, lp, state, opts, num_thread_iterations_before_yield);
+        });
+    }
+}
+
+// Builds the pool that scans and reports run in, so walking, sorting and diffing all stay within `-t` threads
+// The priority is set on the calling thread first, so a nice value or IO class it isn't allowed to use fails here
+pub fn build_thread_pool(maybe_num_threads: Option<usize>, target: &PathBuf, priority: Priority) -> std::io::Result<rayon::ThreadPool> {
+    priority.apply_to_current_thread()?;
+    let num_threads = maybe_num_threads.unwrap_or_else(|| get_auto_num_threads(target));
+    let maybe_pool = rayon::ThreadPoolBuilder::new().num_threads(num_threads).start_handler(move |_| {
+        let _ = priority.apply_to_current_thread();
+    }).build();
+    if maybe_pool.is_err() {
+        return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("failed to build pool of {} threads: {}", num_threads, maybe_pool.err().unwrap())));
+    }
+    return Ok(maybe_pool.unwrap());
+}
+
+enum StorageType {
+    Rotational,
+    Solid,
+    // No local block device, e.g. NFS, FUSE or tmpfs
+    Unknown,
+}
+
+// The walk is mostly waiting on metadata reads, so SSDs get more threads than CPUs to keep their queues full. Rotational
+// disks slow down with concurrent seeks and network filesystems are often shared, so both get a few threads at most
+fn get_auto_num_threads(target: &PathBuf) -> usize {
+    let cpus = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    match get_storage_type(target) {
+        StorageType::Solid => {
+            return std::cmp::min(cpus * 4, 64);
+        }
+        StorageType::Rotational | StorageType::Unknown => {
+            return std::cmp::min(cpus, 4);
         }
     }
-    
-    Ok(res)
-}
-
-fn distribute_paths_per_thread(paths_to_distribute_and_free: &mut Vec<PathBuf>, num_threads: usize) -> Vec<Vec<PathBuf>> {
-    // distribute paths such that each thread gets a "fair" allocation of low and high index elements
-    let max_num_paths_per_thread = (paths_to_distribute_and_free.len() / num_threads) + 1;
-    let mut paths_per_thread: Vec<Vec<PathBuf>> = vec![Vec::with_capacity(max_num_paths_per_thread); num_threads];
-    for i in 0..num_threads {
-        for j in 0..max_num_paths_per_thread {
-            let take_idx = (j * num_threads) + i;
-            if take_idx >= paths_to_distribute_and_free.len() {
-                break;
+}
+
+fn get_storage_type(target: &PathBuf) -> StorageType {
+    let maybe_md = std::fs::metadata(target);
+    if maybe_md.is_err() {
+        return StorageType::Unknown;
+    }
+    let dev = maybe_md.unwrap().dev();
+    let (maj, min) = (libc::major(dev), libc::minor(dev));
+    if maj == 0 {
+        return StorageType::Unknown;
+    }
+
+    // `/sys/dev/block/<maj>:<min>` links to the device, partitions need their parent's name to find the queue
+    let maybe_dev_path = std::fs::canonicalize(format!("/sys/dev/block/{}:{}", maj, min));
+    if maybe_dev_path.is_err() {
+        return StorageType::Unknown;
+    }
+    let mut dev_path = maybe_dev_path.unwrap();
+    if dev_path.join("partition").exists() {
+        dev_path.pop();
+    }
+    let name = dev_path.file_name().unwrap_or_default();
+    let maybe_rotational = std::fs::read_to_string(PathBuf::from("/sys/block").join(name).join("queue/rotational"));
+    match maybe_rotational.as_deref().map(|r| r.trim()) {
+        Ok("1") => {
+            return StorageType::Rotational;
+        }
+        Ok("0") => {
+            return StorageType::Solid;
+        }
+        _ => {
+            return StorageType::Unknown;
+        }
+    }
+}
+
+// Mount points whose filesystem type is one of `fs_types`. Only the last mount at each path counts, it's on top of the
+// others (e.g. an NFS share mounted over its autofs trigger)
+pub fn get_mount_points_of_types(fs_types: &Vec<String>) -> HashSet<PathBuf> {
+    let mut top_mounts: HashMap<PathBuf, String> = HashMap::new();
+    let maybe_mountinfo = std::fs::read_to_string("/proc/self/mountinfo");
+    if maybe_mountinfo.is_err() {
+        return HashSet::new();
+    }
+    // e.g. "36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 - ext3 /dev/root rw,errors=continue"
+    for line in maybe_mountinfo.unwrap().lines() {
+        let fields: Vec<&str> = line.split(' ').collect();
+        let maybe_sep_idx = fields.iter().position(|f| *f == "-");
+        if fields.len() < 5 || maybe_sep_idx.is_none() || maybe_sep_idx.unwrap() + 1 >= fields.len() {
+            continue;
+        }
+        top_mounts.insert(unescape_mount_path(fields[4]), fields[maybe_sep_idx.unwrap() + 1].to_string());
+    }
+    return top_mounts.into_iter().filter(|(_, t)| fs_types.contains(t)).map(|(p, _)| p).collect();
+}
+
+// Spaces, tabs, newlines and backslashes in mountinfo paths are octal escapes, e.g. '\040'
+fn unescape_mount_path(p: &str) -> PathBuf {
+    let bytes = p.as_bytes();
+    let mut ret: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 3 < bytes.len() {
+            let maybe_c = std::str::from_utf8(&bytes[i + 1..i + 4]).map(|oct| u8::from_str_radix(oct, 8));
+            if let Ok(Ok(c)) = maybe_c {
+                ret.push(c);
+                i += 4;
+                continue;
             }
-            paths_per_thread[i].push(paths_to_distribute_and_free[take_idx].clone());
         }
+        ret.push(bytes[i]);
+        i += 1;
     }
-    
-    // the original data is no longer needed, free it
-    paths_to_distribute_and_free.clear();
-    paths_to_distribute_and_free.shrink_to_fit();
+    return PathBuf::from(std::ffi::OsString::from_vec(ret));
+}
 
-    return paths_per_thread;
+// Maps the ids in an `/etc/passwd` or `/etc/group` style file to their names, a missing file has no names
+pub fn read_id_names(p: &str) -> HashMap<u32, String> {
+    let mut names: HashMap<u32, String> = HashMap::new();
+    let maybe_contents = std::fs::read_to_string(p);
+    if maybe_contents.is_err() {
+        return names;
+    }
+    for line in maybe_contents.unwrap().lines() {
+        let fields: Vec<&str> = line.split(':').collect();
+        if line.starts_with('#') || fields.len() < 3 {
+            continue;
+        }
+        if let Ok(id) = fields[2].parse::<u32>() {
+            names.entry(id).or_insert(fields[0].to_string());
+        }
+    }
+    return names;
 }
 
 pub fn datetime_from_iso8601_without_tz(datetime_string: &str, tz_offset_secs: i32) -> chrono::ParseResult<chrono::DateTime<chrono::FixedOffset>> {
@@ -142,10 +301,47 @@ fn tz_secs_to_tz_str(tz_secs: i32) -> String {
     return format!("{}{:02}:{:02}", sign, hours, mins)
 }
 
+// Number of bytes hashed from each end of a file by `HashMode::Quick`
+const QUICK_HASH_BYTES: u64 = 8 * KILOBYTE as u64;
+
+pub fn get_md5_of_file(p: &PathBuf, mode: HashMode) -> std::io::Result<[u8; 16]> {
+    let mut f = File::open(p)?;
+    let mut hasher = md5::new();
+    let mut buf = vec![0; 64 * KILOBYTE];
+    match mode {
+        HashMode::None => {}
+        HashMode::Quick => {
+            let sz = f.metadata()?.len();
+            let head = std::cmp::min(sz, QUICK_HASH_BYTES) as usize;
+            f.read_exact(&mut buf[..head])?;
+            hasher.update(&buf[..head]);
+            if sz > QUICK_HASH_BYTES {
+                let tail_start = std::cmp::max(sz - QUICK_HASH_BYTES, QUICK_HASH_BYTES);
+                let tail = (sz - tail_start) as usize;
+                f.seek(SeekFrom::Start(tail_start))?;
+                f.read_exact(&mut buf[..tail])?;
+                hasher.update(&buf[..tail]);
+            }
+        }
+        HashMode::Full => {
+            loop {
+                let n = f.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buf[..n]);
+            }
+        }
+    }
+    return Ok(hasher.digest().into_inner());
+}
+
 pub fn get_md5_of_cdirentry(mut inp: CDirEntry) -> [u8; 16] {
     let zero_md5: [u8; 16] = [0; 16];
     
+    // Renaming a directory changes its ctime, which would stop moves from matching
     inp.p = PathBuf::new();
+    inp.ct = None;
     inp.md5 = [0; 16];
     let res = bincode::serialize(&inp);
     if res.is_err() {
@@ -158,4 +354,65 @@ pub fn get_md5_of_cdirentry(mut inp: CDirEntry) -> [u8; 16] {
     }
     
     return digest.unwrap().into_inner();
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+
+    fn glob_matches(glob: &str, s: &str) -> bool {
+        return Regex::new(&glob_to_regex(glob)).unwrap().is_match(s);
+    }
+
+    #[test]
+    fn glob_to_regex_translates_globs() {
+        // `*` stays within a path component
+        assert_eq!(glob_to_regex("*.log"), "^[^/]*\\.log$");
+        assert!(glob_matches("*.log", "a.log"));
+        assert!(glob_matches("*.log", ".log"));
+        assert!(!glob_matches("*.log", "a.log.1"));
+        assert!(!glob_matches("/var/*.log", "/var/log/a.log"));
+
+        // `**` crosses them
+        assert_eq!(glob_to_regex("/var/**.log"), "^/var/.*\\.log$");
+        assert!(glob_matches("/var/**.log", "/var/log/a.log"));
+        assert!(glob_matches("/home/**/cache", "/home/u/.local/cache"));
+        assert!(!glob_matches("/home/**/cache", "/home/u/cache/x"));
+
+        // `?` is exactly one character, not a separator
+        assert!(glob_matches("a?c", "abc"));
+        assert!(!glob_matches("a?c", "ac"));
+        assert!(!glob_matches("a?c", "a/c"));
+
+        // Character classes, `[!x]` is negated
+        assert_eq!(glob_to_regex("[!x]y"), "^[^x]y$");
+        assert!(glob_matches("[!x]y", "ay"));
+        assert!(!glob_matches("[!x]y", "xy"));
+        assert!(glob_matches("[ab]*", "b1"));
+        assert!(!glob_matches("[ab]*", "c1"));
+
+        // An unclosed `[` is a literal one
+        assert_eq!(glob_to_regex("a[b"), "^a\\[b$");
+        assert!(glob_matches("a[b", "a[b"));
+        assert!(glob_matches("*[", "x["));
+
+        // Everything else is literal
+        assert!(glob_matches("a+b(c).d", "a+b(c).d"));
+        assert!(!glob_matches("a.b", "axb"));
+    }
+
+    #[test]
+    fn unescape_mount_path_decodes_octal_escapes() {
+        assert_eq!(unescape_mount_path("/mnt/usb"), PathBuf::from("/mnt/usb"));
+        assert_eq!(unescape_mount_path(r"/mnt/my\040disk"), PathBuf::from("/mnt/my disk"));
+        assert_eq!(unescape_mount_path(r"/mnt/a\040b\011c\012d\134e"), PathBuf::from("/mnt/a b\tc\nd\\e"));
+        // At the very end of the path
+        assert_eq!(unescape_mount_path(r"/mnt/x\040"), PathBuf::from("/mnt/x "));
+        // Anything that isn't three octal digits is kept as is
+        assert_eq!(unescape_mount_path(r"/mnt/a\04"), PathBuf::from(r"/mnt/a\04"));
+        assert_eq!(unescape_mount_path(r"/mnt/a\089"), PathBuf::from(r"/mnt/a\089"));
+        // Escaped bytes that aren't UTF-8 on their own
+        assert_eq!(unescape_mount_path(r"/mnt/\303\251"), PathBuf::from("/mnt/\u{e9}"));
+    }
+}
@@ -1,22 +1,113 @@
-use std::fs::metadata;
-use std::{ffi::OsString, fs::DirEntry, os::unix::fs::MetadataExt, time::SystemTime};
-use std::{collections::HashMap, fs::{symlink_metadata, Metadata}, path::PathBuf};
+use std::fs::{metadata, read_link};
+use std::{ffi::OsString, fs::DirEntry, os::unix::fs::{FileTypeExt, MetadataExt}, sync::atomic::Ordering, time::SystemTime};
+use std::{collections::{HashMap, HashSet}, fs::{symlink_metadata, Metadata}, path::PathBuf};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use crate::utility;
+use crate::{checkpoint, progress::Progress, scan::ScanOptions, throttle::RateLimiter, utility};
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct FileEntry {
     pub sz: u64,
+    // Bytes actually allocated on disk (st_blocks * 512), differs from `sz` for sparse or compressed files
+    pub alloc_sz: u64,
     pub bn: OsString,
     pub md: Option<SystemTime>,
+
+    // Identifies hard links to the same inode, so their bytes are only counted once
+    pub dev: u64,
+    pub ino: u64,
+    pub nlink: u64,
+
+    // Only set for symlinks, `dangling` is true when the target doesn't resolve
+    pub target: Option<PathBuf>,
+    pub dangling: bool,
+
+    // Content digest, only set for regular files when scanning with `--hash`
+    pub hash: Option<[u8; 16]>,
+    pub kind: FileKind,
+    pub attrs: Attrs,
 }
 impl Default for FileEntry {
     fn default() -> Self {
         FileEntry {
             sz: 0,
+            alloc_sz: 0,
             bn: OsString::new(),
             md: None,
+            dev: 0,
+            ino: 0,
+            nlink: 0,
+            target: None,
+            dangling: false,
+            hash: None,
+            kind: FileKind::File,
+            attrs: Attrs::default(),
+        }
+    }
+}
+
+// Ownership and permission bits (without the file type) of a file or directory
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct Attrs {
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u32,
+}
+impl Attrs {
+    fn from_metadata(md: &Metadata) -> Self {
+        return Attrs {
+            uid: md.uid(),
+            gid: md.gid(),
+            mode: md.mode() & 0o7777,
+        };
+    }
+}
+
+// What a non-directory entry is. Special files (FIFOs, sockets and device nodes) are kept apart from files, their sizes
+// don't mean anything
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum FileKind {
+    #[default]
+    File,
+    Symlink,
+    Fifo,
+    Socket,
+    BlockDevice,
+    CharDevice,
+}
+impl FileKind {
+    fn from_metadata(md: &Metadata) -> Self {
+        let ft = md.file_type();
+        if ft.is_symlink() {
+            return FileKind::Symlink;
+        } else if ft.is_fifo() {
+            return FileKind::Fifo;
+        } else if ft.is_socket() {
+            return FileKind::Socket;
+        } else if ft.is_block_device() {
+            return FileKind::BlockDevice;
+        } else if ft.is_char_device() {
+            return FileKind::CharDevice;
         }
+        return FileKind::File;
+    }
+
+    pub fn is_special(&self) -> bool {
+        return *self != FileKind::File && *self != FileKind::Symlink;
+    }
+}
+impl std::fmt::Display for FileKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            FileKind::File => "file",
+            FileKind::Symlink => "symlink",
+            FileKind::Fifo => "fifo",
+            FileKind::Socket => "socket",
+            FileKind::BlockDevice => "block device",
+            FileKind::CharDevice => "char device",
+        };
+        return write!(f, "{}", s);
     }
 }
 
@@ -28,16 +119,257 @@ pub struct CDirEntry {
     pub dirs_below: usize,
     pub size_here: i64,
     pub size_below: i64,
+    // Same as `size_*` but each inode is only counted once per root, populated by `bubble_up_props`
+    pub unique_size_here: i64,
+    pub unique_size_below: i64,
+    pub alloc_here: i64,
+    pub alloc_below: i64,
+    // Special files aren't counted in `files_*` or the sizes
+    pub specials_here: usize,
+    pub specials_below: usize,
     
     pub p: PathBuf,
     pub md: Option<SystemTime>,
+    // Status change time, used by incremental scans to tell if a directory can be reused without reading it
+    pub ct: Option<SystemTime>,
     pub md5: [u8; 16],
 
     pub files: Vec<FileEntry>,
     pub symlinks: Vec<FileEntry>,
+    pub specials: Vec<FileEntry>,
+    pub attrs: Attrs,
+
+    // Set for mount points skipped by `--one-file-system`, these are recorded but never read
+    pub not_traversed: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum WalkPhase {
+    DirMetadata,
+    ReadDir,
+    DirEntry,
+    FileMetadata,
+    Hash,
+}
+impl std::fmt::Display for WalkPhase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            WalkPhase::DirMetadata => "stat dir",
+            WalkPhase::ReadDir => "read_dir",
+            WalkPhase::DirEntry => "read dir entry",
+            WalkPhase::FileMetadata => "stat file",
+            WalkPhase::Hash => "hash file",
+        };
+        return write!(f, "{}", s);
+    }
+}
+
+// A path that couldn't be read during the walk, everything else is still collected
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WalkError {
+    pub p: PathBuf,
+    pub errno: Option<i32>,
+    pub phase: WalkPhase,
+}
+impl WalkError {
+    fn new(p: PathBuf, e: &std::io::Error, phase: WalkPhase) -> Self {
+        return WalkError {
+            p: p,
+            errno: e.raw_os_error(),
+            phase: phase,
+        };
+    }
+
+    // Errors that mean a directory's contents are missing from the scan
+    pub fn is_dir_error(&self) -> bool {
+        return self.phase == WalkPhase::DirMetadata || self.phase == WalkPhase::ReadDir;
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum HashMode {
+    #[default]
+    None,
+    // Only the first and last few KiB of each file
+    Quick,
+    Full,
+}
+
+// Compiled form of the `ScanOptions` that affect which paths the walk visits
+pub struct WalkOptions<'a> {
+    exclude: Vec<PathPattern>,
+    include: Vec<PathPattern>,
+    pub roots: Vec<WalkRoot>,
+    hash: HashMode,
+    // Directories deeper than `max_depth` below their root are rolled into their ancestor
+    max_depth: Option<usize>,
+    // Mount points of the filesystem types in `ScanOptions::skip_fs_types`
+    skip_mounts: HashSet<PathBuf>,
+    // Previous state for incremental scans
+    pub prev_scan: Option<PrevScan<'a>>,
+    pub progress: Option<&'a Progress>,
+    // Limits the rate of `read_dir` and `metadata` calls, with `--iops-limit`
+    pub rate_limit: Option<&'a RateLimiter>,
+}
+impl<'a> WalkOptions<'a> {
+    pub fn new(opts: &ScanOptions) -> std::io::Result<Self> {
+        let mut exclude = Vec::with_capacity(opts.exclude.len());
+        for p in &opts.exclude {
+            exclude.push(PathPattern::new(p)?);
+        }
+        let mut include = Vec::with_capacity(opts.include.len());
+        for p in &opts.include {
+            include.push(PathPattern::new(p)?);
+        }
+        return Ok(WalkOptions {
+            exclude: exclude,
+            include: include,
+            roots: vec![],
+            hash: opts.hash,
+            max_depth: opts.max_depth,
+            skip_mounts: utility::get_mount_points_of_types(&opts.skip_fs_types),
+            prev_scan: None,
+            progress: None,
+            rate_limit: None,
+        });
+    }
+
+    fn throttle(&self) {
+        if let Some(rl) = self.rate_limit {
+            rl.take();
+        }
+    }
+
+    fn is_excluded(&self, p: &PathBuf) -> bool {
+        return self.exclude.iter().any(|pat| pat.is_match(p));
+    }
+
+    fn get_root(&self, p: &PathBuf) -> Option<&WalkRoot> {
+        return self.roots.iter().find(|r| p.starts_with(&r.p));
+    }
+
+    // With `--one-file-system`, directories on a different device to their root are recorded as `not_traversed`
+    fn is_other_device(&self, p: &PathBuf, md: &Metadata) -> bool {
+        let Some(root) = self.get_root(p) else { return false };
+        return root.dev.is_some() && md.dev() != root.dev.unwrap();
+    }
+
+    // Skipped filesystems are still walked when they're a root, the user asked for them
+    fn is_skipped_mount(&self, p: &PathBuf) -> bool {
+        return self.skip_mounts.contains(p) && !self.roots.iter().any(|r| &r.p == p);
+    }
+
+    fn is_at_max_depth(&self, p: &PathBuf) -> bool {
+        let (Some(max_depth), Some(root)) = (self.max_depth, self.get_root(p)) else { return false };
+        return p.components().count().saturating_sub(root.depth) >= max_depth;
+    }
+
+    // Include patterns only restrict which files are recorded, directories are still walked
+    fn is_file_included(&self, p: &PathBuf) -> bool {
+        return self.include.len() == 0 || self.include.iter().any(|pat| pat.is_match(p));
+    }
+}
+
+// A path the walk starts from, scans of a profile have one per member root
+pub struct WalkRoot {
+    pub p: PathBuf,
+    pub dev: Option<u64>,
+    depth: usize,
+}
+impl WalkRoot {
+    pub fn new(p: &PathBuf, one_file_system: bool) -> std::io::Result<Self> {
+        let mut dev = None;
+        if one_file_system {
+            dev = Some(symlink_metadata(p)?.dev());
+        }
+        return Ok(WalkRoot {
+            p: p.clone(),
+            dev: dev,
+            depth: p.components().count(),
+        });
+    }
+}
+
+// Lookups into a path sorted previous state, to copy directories that haven't changed since
+pub struct PrevScan<'a> {
+    entries: &'a Vec<CDirEntry>,
+    path_idxs: HashMap<&'a PathBuf, usize>,
+    child_idxs: HashMap<&'a std::path::Path, Vec<usize>>,
+}
+impl<'a> PrevScan<'a> {
+    pub fn new(entries: &'a Vec<CDirEntry>) -> Self {
+        let mut path_idxs = HashMap::with_capacity(entries.len());
+        let mut child_idxs: HashMap<&std::path::Path, Vec<usize>> = HashMap::new();
+        for i in 0..entries.len() {
+            path_idxs.insert(&entries[i].p, i);
+            if let Some(parent) = entries[i].p.parent() {
+                child_idxs.entry(parent).or_default().push(i);
+            }
+        }
+        return PrevScan {
+            entries: entries,
+            path_idxs: path_idxs,
+            child_idxs: child_idxs,
+        };
+    }
+
+    // A directory's entries can only change if its mtime and ctime do
+    fn get_unchanged(&self, p: &PathBuf, md: &Metadata) -> Option<&'a CDirEntry> {
+        let idx = self.path_idxs.get(p)?;
+        let prev = &self.entries[*idx];
+        if prev.not_traversed || prev.md.is_none() || prev.ct.is_none() {
+            return None;
+        }
+        if prev.md != md.modified().ok() || prev.ct != get_ctime(md) {
+            return None;
+        }
+        return Some(prev);
+    }
+
+    fn get_child_dirs(&self, p: &PathBuf) -> Vec<PathBuf> {
+        let Some(idxs) = self.child_idxs.get(p.as_path()) else { return vec![] };
+        return idxs.iter().map(|i| self.entries[*i].p.clone()).collect();
+    }
+}
+
+fn get_ctime(md: &Metadata) -> Option<SystemTime> {
+    if md.ctime() < 0 {
+        return None;
+    }
+    return Some(SystemTime::UNIX_EPOCH + std::time::Duration::new(md.ctime() as u64, md.ctime_nsec() as u32));
+}
+
+// A glob, or a regex when prefixed with "re:". Globs without a '/' match the basename, everything else matches the full path
+struct PathPattern {
+    re: Regex,
+    match_basename: bool,
+}
+impl PathPattern {
+    fn new(pattern: &str) -> std::io::Result<Self> {
+        let (re_str, match_basename) = match pattern.strip_prefix("re:") {
+            Some(re_str) => (re_str.to_string(), false),
+            None => (utility::glob_to_regex(pattern), !pattern.contains('/')),
+        };
+        let maybe_re = Regex::new(&re_str);
+        if maybe_re.is_err() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("invalid pattern '{}': {}", pattern, maybe_re.err().unwrap())));
+        }
+        return Ok(PathPattern {
+            re: maybe_re.unwrap(),
+            match_basename: match_basename,
+        });
+    }
+
+    fn is_match(&self, p: &PathBuf) -> bool {
+        if self.match_basename {
+            let Some(bn) = p.file_name() else { return false };
+            return self.re.is_match(&bn.to_string_lossy());
+        }
+        return self.re.is_match(&p.to_string_lossy());
+    }
 }
 
-pub fn walk_collect_until_limit(some: &mut Vec<std::path::PathBuf>, other_entries: &mut Vec<CDirEntry>, thread_readdir_limit: usize) -> std::io::Result<Vec<PathBuf>> {
+pub fn walk_collect_until_limit(some: &mut Vec<std::path::PathBuf>, other_entries: &mut Vec<CDirEntry>, errors: &mut Vec<WalkError>, opts: &WalkOptions, thread_readdir_limit: usize) -> Vec<PathBuf> {
     let mut d_idx = 0;
     let mut f_idx = 0;
     
@@ -46,57 +378,291 @@ pub fn walk_collect_until_limit(some: &mut Vec<std::path::PathBuf>, other_entrie
         readdir_limit = some.len();
     }
     
+    // The paths in `some` were already counted as queued by whoever found them
+    let mut reported = (0, 0, 0, some.len());
+    let mut bytes: u64 = 0;
+    // Directories walked below the max depth, only for progress
+    let mut rolled_up_d = 0;
     let mut dir_q: Vec<PathBuf> = Vec::with_capacity(readdir_limit);
     dir_q.append(some);
 
     let mut pm = HashMap::new();
     while (d_idx + f_idx) < readdir_limit && d_idx < dir_q.len() {
-        let rd = std::fs::read_dir(&dir_q[d_idx]);
-        if rd.is_err() {
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("{:?}", rd.err())));
+        report_progress(opts, &mut reported, d_idx + rolled_up_d, f_idx, bytes, dir_q.len() - d_idx);
+        if checkpoint::is_interrupted() {
+            break;
         }
 
+        // Unreadable directories are recorded and skipped, the rest of the batch is still walked
+        opts.throttle();
         let maybe_md = symlink_metadata(&dir_q[d_idx]);
         if maybe_md.is_err() {
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("{:?}", maybe_md.err())));
+            errors.push(WalkError::new(dir_q[d_idx].clone(), &maybe_md.err().unwrap(), WalkPhase::DirMetadata));
+            d_idx += 1;
+            continue;
         }
         let md = maybe_md.unwrap();
 
+        if opts.is_other_device(&dir_q[d_idx], &md) || opts.is_skipped_mount(&dir_q[d_idx]) {
+            let curr_idx = insert_dir_entry(&md, &dir_q[d_idx], other_entries, &mut pm);
+            other_entries[curr_idx].not_traversed = true;
+            other_entries[curr_idx].md5 = utility::get_md5_of_cdirentry(other_entries[curr_idx].clone());
+            d_idx += 1;
+            continue;
+        }
+
+        // Incremental scans copy unchanged directories, only their subdirectories need to be checked. Directories at the max
+        // depth hold the totals of their whole subtree, which can change without changing their mtime
+        let at_max_depth = opts.is_at_max_depth(&dir_q[d_idx]);
+        if opts.prev_scan.is_some() && !at_max_depth {
+            let prev_scan = opts.prev_scan.as_ref().unwrap();
+            if let Some(prev) = prev_scan.get_unchanged(&dir_q[d_idx], &md) {
+                let curr_idx = insert_dir_entry(&md, &dir_q[d_idx], other_entries, &mut pm);
+                other_entries[curr_idx].files_here = prev.files_here;
+                other_entries[curr_idx].size_here = prev.size_here;
+                other_entries[curr_idx].alloc_here = prev.alloc_here;
+                other_entries[curr_idx].files = prev.files.clone();
+                other_entries[curr_idx].symlinks = prev.symlinks.clone();
+                other_entries[curr_idx].specials_here = prev.specials_here;
+                other_entries[curr_idx].specials = prev.specials.clone();
+                other_entries[curr_idx].md5 = utility::get_md5_of_cdirentry(other_entries[curr_idx].clone());
+                dir_q.append(&mut prev_scan.get_child_dirs(&dir_q[d_idx]));
+                f_idx += prev.files_here;
+                bytes += prev.size_here as u64;
+                d_idx += 1;
+                continue;
+            }
+        }
+
+        opts.throttle();
+        let rd = std::fs::read_dir(&dir_q[d_idx]);
+        if rd.is_err() {
+            errors.push(WalkError::new(dir_q[d_idx].clone(), &rd.err().unwrap(), WalkPhase::ReadDir));
+            d_idx += 1;
+            continue;
+        }
+
         let curr_idx = insert_dir_entry(&md, &dir_q[d_idx], other_entries, &mut pm);
         let entries: Vec<Result<DirEntry, std::io::Error>> = rd.unwrap().collect();
         let mut file_entries: Vec<FileEntry> = Vec::with_capacity(entries.len());
         let mut symlink_entries: Vec<FileEntry> = Vec::with_capacity(entries.len());
+        let mut special_entries: Vec<FileEntry> = vec![];
+        let mut hash_idxs: Vec<usize> = vec![];
+        let mut rolled_up_dirs: Vec<PathBuf> = vec![];
         for ent in entries {
-            let Ok(val) = ent else { continue };
-            let Ok(ft) = val.file_type() else { continue };
+            let val = match ent {
+                Ok(val) => val,
+                Err(e) => {
+                    errors.push(WalkError::new(dir_q[d_idx].clone(), &e, WalkPhase::DirEntry));
+                    continue;
+                }
+            };
+            let ft = match val.file_type() {
+                Ok(ft) => ft,
+                Err(e) => {
+                    errors.push(WalkError::new(val.path(), &e, WalkPhase::DirEntry));
+                    continue;
+                }
+            };
                 
+            let ent_path = val.path();
+            if opts.is_excluded(&ent_path) {
+                continue;
+            }
             if ft.is_dir() {
-                dir_q.push(val.path());
+                if at_max_depth {
+                    rolled_up_dirs.push(ent_path);
+                } else {
+                    dir_q.push(ent_path);
+                }
+                continue;
+            }
+            if !opts.is_file_included(&ent_path) {
                 continue;
             }
 
-            let Ok(fmd) = metadata(val.path()) else {continue};    
+            opts.throttle();
+            let fmd = match symlink_metadata(&ent_path) {
+                Ok(fmd) => fmd,
+                Err(e) => {
+                    errors.push(WalkError::new(ent_path, &e, WalkPhase::FileMetadata));
+                    continue;
+                }
+            };
                 
             f_idx += 1;
             let filename = val.file_name();
+            if FileKind::from_metadata(&fmd).is_special() {
+                insert_file_entry(&fmd, filename, &mut special_entries);
+                other_entries[curr_idx].specials_here += 1;
+                continue;
+            }
             if fmd.is_symlink() {
-                insert_file_entry(&fmd, filename, &mut symlink_entries);
+                let s_idx = insert_file_entry(&fmd, filename, &mut symlink_entries);
+                match read_link(&ent_path) {
+                    Ok(target) => {
+                        symlink_entries[s_idx].target = Some(target);
+                        opts.throttle();
+                        symlink_entries[s_idx].dangling = metadata(&ent_path).is_err();
+                    }
+                    Err(e) => {
+                        errors.push(WalkError::new(ent_path, &e, WalkPhase::FileMetadata));
+                    }
+                }
             } else {
-                insert_file_entry(&fmd, filename, &mut file_entries);
+                let file_idx = insert_file_entry(&fmd, filename, &mut file_entries);
+                if opts.hash != HashMode::None && fmd.is_file() {
+                    hash_idxs.push(file_idx);
+                }
             }
     
             other_entries[curr_idx].files_here += 1;
             other_entries[curr_idx].size_here += fmd.size() as i64;
+            bytes += fmd.size();
+            other_entries[curr_idx].alloc_here += (fmd.blocks() * 512) as i64;
         }        
+
+        // Subdirectories below the max depth don't get entries, their totals are added to this directory's instead. An
+        // interrupted subtree is walked again from this directory on resume
+        if rolled_up_dirs.len() > 0 {
+            let num_subdirs = rolled_up_dirs.len();
+            let Some(r) = walk_rolled_up(rolled_up_dirs, errors, opts) else {
+                other_entries.pop();
+                break;
+            };
+            other_entries[curr_idx].dirs_here = num_subdirs;
+            other_entries[curr_idx].dirs_below = r.dirs - num_subdirs;
+            other_entries[curr_idx].files_here += r.files;
+            other_entries[curr_idx].size_here += r.size;
+            other_entries[curr_idx].alloc_here += r.alloc;
+            other_entries[curr_idx].specials_here += r.specials;
+            rolled_up_d += r.dirs;
+            f_idx += r.files;
+            bytes += r.size as u64;
+        }
+
+        // Hash this directory's files on the rayon pool, while other directories are still being walked
+        let dir_path = &dir_q[d_idx];
+        let hashes: Vec<(usize, std::io::Result<[u8; 16]>)> = hash_idxs.par_iter().map(|i| {
+            let fp = dir_path.join(&file_entries[*i].bn);
+            return (*i, utility::get_md5_of_file(&fp, opts.hash));
+        }).collect();
+        for (i, res) in hashes {
+            match res {
+                Ok(digest) => {
+                    file_entries[i].hash = Some(digest);
+                }
+                Err(e) => {
+                    errors.push(WalkError::new(dir_path.join(&file_entries[i].bn), &e, WalkPhase::Hash));
+                }
+            }
+        }
+
+        // File diffs walk the old and new lists in name order
+        file_entries.sort_by(|a, b| a.bn.cmp(&b.bn));
+        symlink_entries.sort_by(|a, b| a.bn.cmp(&b.bn));
+        special_entries.sort_by(|a, b| a.bn.cmp(&b.bn));
         other_entries[curr_idx].symlinks = symlink_entries;
+        other_entries[curr_idx].specials = special_entries;
         other_entries[curr_idx].files = file_entries;
 
         other_entries[curr_idx].md5 = utility::get_md5_of_cdirentry(other_entries[curr_idx].clone());
 
         d_idx += 1;
     }
+    report_progress(opts, &mut reported, d_idx + rolled_up_d, f_idx, bytes, dir_q.len() - d_idx);
+
+    return dir_q.drain(d_idx..).collect();
+}
+
+// Totals of the directories below `--max-depth` in a subtree
+#[derive(Default)]
+struct RolledUp {
+    dirs: usize,
+    files: usize,
+    size: i64,
+    alloc: i64,
+    specials: usize,
+}
+
+// Walks the subtrees of `dirs` without recording entries, only their totals. Returns None if the walk was interrupted, then
+// nothing is added to `errors` either
+fn walk_rolled_up(mut dirs: Vec<PathBuf>, errors: &mut Vec<WalkError>, opts: &WalkOptions) -> Option<RolledUp> {
+    let mut r = RolledUp::default();
+    let mut new_errors: Vec<WalkError> = vec![];
+    while let Some(d) = dirs.pop() {
+        if checkpoint::is_interrupted() {
+            return None;
+        }
+        r.dirs += 1;
+
+        opts.throttle();
+        let md = match symlink_metadata(&d) {
+            Ok(md) => md,
+            Err(e) => {
+                new_errors.push(WalkError::new(d, &e, WalkPhase::DirMetadata));
+                continue;
+            }
+        };
+        if opts.is_other_device(&d, &md) || opts.is_skipped_mount(&d) {
+            continue;
+        }
+        opts.throttle();
+        let rd = match std::fs::read_dir(&d) {
+            Ok(rd) => rd,
+            Err(e) => {
+                new_errors.push(WalkError::new(d, &e, WalkPhase::ReadDir));
+                continue;
+            }
+        };
+        for ent in rd {
+            let val = match ent {
+                Ok(val) => val,
+                Err(e) => {
+                    new_errors.push(WalkError::new(d.clone(), &e, WalkPhase::DirEntry));
+                    continue;
+                }
+            };
+            let ent_path = val.path();
+            if opts.is_excluded(&ent_path) {
+                continue;
+            }
+            opts.throttle();
+            let fmd = match symlink_metadata(&ent_path) {
+                Ok(fmd) => fmd,
+                Err(e) => {
+                    new_errors.push(WalkError::new(ent_path, &e, WalkPhase::FileMetadata));
+                    continue;
+                }
+            };
+            if fmd.is_dir() {
+                dirs.push(ent_path);
+                continue;
+            }
+            if !opts.is_file_included(&ent_path) {
+                continue;
+            }
+            if FileKind::from_metadata(&fmd).is_special() {
+                r.specials += 1;
+                continue;
+            }
+            r.files += 1;
+            r.size += fmd.size() as i64;
+            r.alloc += (fmd.blocks() * 512) as i64;
+        }
+    }
+    errors.append(&mut new_errors);
+    return Some(r);
+}
 
-    return Ok(dir_q.drain(d_idx..).collect());
+// Adds what this batch walked since the last call to the shared progress counters
+fn report_progress(opts: &WalkOptions, reported: &mut (usize, usize, u64, usize), dirs: usize, files: usize, bytes: u64, pending: usize) {
+    let Some(p) = opts.progress else { return };
+    p.dirs.fetch_add(dirs - reported.0, Ordering::Relaxed);
+    p.files.fetch_add(files - reported.1, Ordering::Relaxed);
+    p.bytes.fetch_add(bytes - reported.2, Ordering::Relaxed);
+    p.add_queued(pending as isize - reported.3 as isize);
+    *reported = (dirs, files, bytes, pending);
 }
 
 fn insert_file_entry(md: &Metadata, bn: OsString, dest: &mut Vec<FileEntry>) -> usize {
@@ -107,7 +673,16 @@ fn insert_file_entry(md: &Metadata, bn: OsString, dest: &mut Vec<FileEntry>) ->
     let e = FileEntry{
         bn: bn,
         sz: md.len(),
+        alloc_sz: md.blocks() * 512,
         md: t,
+        dev: md.dev(),
+        ino: md.ino(),
+        nlink: md.nlink(),
+        target: None,
+        dangling: false,
+        hash: None,
+        kind: FileKind::from_metadata(md),
+        attrs: Attrs::from_metadata(md),
     };
     dest.push(e);
     return dest.len() - 1;
@@ -122,6 +697,7 @@ fn insert_dir_entry(md: &Metadata, p: &PathBuf, all_dirs: &mut Vec<CDirEntry>, p
     let e = CDirEntry{
         p: pb.clone(),
         md: t,
+        ct: get_ctime(md),
 
         files_here: 0,
         files_below: 0,
@@ -129,12 +705,81 @@ fn insert_dir_entry(md: &Metadata, p: &PathBuf, all_dirs: &mut Vec<CDirEntry>, p
         dirs_below: 0,
         size_here: 0,
         size_below: 0,
+        unique_size_here: 0,
+        unique_size_below: 0,
+        alloc_here: 0,
+        alloc_below: 0,
+        specials_here: 0,
+        specials_below: 0,
         md5: [0; 16],
 
         files: vec![],
         symlinks: vec![],
+        specials: vec![],
+        attrs: Attrs::from_metadata(md),
+        not_traversed: false,
     };
     all_dirs.push(e);
     path_idx_map.insert(pb, all_dirs.len() - 1);
     return all_dirs.len() - 1;                                           
-}
\ No newline at end of file
+}
+#[cfg(test)]
+impl CDirEntry {
+    // An entry of directory `p` owned by root, with `files` directly in it and nothing below
+    pub fn new_for_test(p: &str, files: Vec<FileEntry>) -> Self {
+        let size: i64 = files.iter().map(|f| f.sz as i64).sum();
+        let alloc: i64 = files.iter().map(|f| f.alloc_sz as i64).sum();
+        return CDirEntry {
+            files_here: files.len(),
+            files_below: 0,
+            dirs_here: 0,
+            dirs_below: 0,
+            size_here: size,
+            size_below: 0,
+            unique_size_here: size,
+            unique_size_below: 0,
+            alloc_here: alloc,
+            alloc_below: 0,
+            specials_here: 0,
+            specials_below: 0,
+            p: PathBuf::from(p),
+            md: None,
+            ct: None,
+            md5: [0; 16],
+            files: files,
+            symlinks: vec![],
+            specials: vec![],
+            attrs: Attrs { uid: 0, gid: 0, mode: 0o755 },
+            not_traversed: false,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_pattern_matches_basename_or_full_path() {
+        // Without a '/' the basename is matched, in any directory
+        let pattern = PathPattern::new("*.tmp").unwrap();
+        assert!(pattern.is_match(&PathBuf::from("/a/b/c.tmp")));
+        assert!(!pattern.is_match(&PathBuf::from("/a/b.tmp/c")));
+        assert!(!pattern.is_match(&PathBuf::from("/")));
+
+        // With one the full path is, from the start
+        let pattern = PathPattern::new("/a/*/c.tmp").unwrap();
+        assert!(pattern.is_match(&PathBuf::from("/a/b/c.tmp")));
+        assert!(!pattern.is_match(&PathBuf::from("/x/a/b/c.tmp")));
+        let pattern = PathPattern::new("b/*.tmp").unwrap();
+        assert!(!pattern.is_match(&PathBuf::from("/a/b/c.tmp")));
+        let pattern = PathPattern::new("**/b/*.tmp").unwrap();
+        assert!(pattern.is_match(&PathBuf::from("/a/b/c.tmp")));
+
+        // Regexes always match the full path, unanchored
+        let pattern = PathPattern::new("re:/b/[0-9]+$").unwrap();
+        assert!(pattern.is_match(&PathBuf::from("/a/b/12")));
+        assert!(!pattern.is_match(&PathBuf::from("/a/b/12x")));
+        assert!(PathPattern::new("re:(").is_err());
+    }
+}